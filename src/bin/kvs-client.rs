@@ -1,5 +1,8 @@
 use std::env;
 use std::net::SocketAddr;
+use std::num::NonZeroU64;
+use std::process;
+use std::time::Duration;
 
 use log::info;
 use structopt::StructOpt;
@@ -27,6 +30,18 @@ enum ClientCmd {
         key: String,
         #[structopt(name = "VALUE")]
         value: String,
+        /// Only set the key if it does not exist yet
+        #[structopt(long, conflicts_with = "if-present")]
+        if_absent: bool,
+        /// Only set the key if it already exists
+        #[structopt(long)]
+        if_present: bool,
+        /// Expire the key after this many seconds
+        #[structopt(long, conflicts_with_all = &["px", "if-absent", "if-present"])]
+        ex: Option<NonZeroU64>,
+        /// Expire the key after this many milliseconds
+        #[structopt(long, conflicts_with_all = &["if-absent", "if-present"])]
+        px: Option<NonZeroU64>,
         /// IP:PORT
         #[structopt(short, long, default_value = "127.0.0.1:4000")]
         addr: SocketAddr,
@@ -47,6 +62,20 @@ enum ClientCmd {
         #[structopt(short, long, default_value = "127.0.0.1:4000")]
         addr: SocketAddr,
     },
+    /// Set a key to a new value if it still has the expected one
+    Cas {
+        #[structopt(name = "KEY")]
+        key: String,
+        /// Value the key must have, the key must not exist if omitted
+        #[structopt(long)]
+        expected: Option<String>,
+        /// Value to set, the key is removed if omitted
+        #[structopt(long)]
+        new: Option<String>,
+        /// IP:PORT
+        #[structopt(short, long, default_value = "127.0.0.1:4000")]
+        addr: SocketAddr,
+    },
 }
 
 fn main() -> Result<()> {
@@ -61,17 +90,67 @@ fn main() -> Result<()> {
                 .unwrap_or_else(|| "Key not found".to_owned());
             println!("{}", out);
         }
-        ClientCmd::Set { key, value, addr } => {
+        ClientCmd::Set {
+            key,
+            value,
+            if_absent,
+            if_present,
+            ex,
+            px,
+            addr,
+        } => {
             info!("client {} target {}", env!("CARGO_PKG_VERSION"), addr);
             let mut client = KvsClient::connect(addr)?;
-            client.set(key, value)?;
+            let (key, value) = (key.into_bytes(), value.into_bytes());
+            let ttl = match (ex, px) {
+                (Some(secs), _) => Some(Duration::from_secs(secs.get())),
+                (_, Some(millis)) => Some(Duration::from_millis(millis.get())),
+                _ => None,
+            };
+            let done = if let Some(ttl) = ttl {
+                client.set_with_ttl(key, value, ttl)?;
+                true
+            } else if if_absent {
+                client.set_if_absent(key, value)?
+            } else if if_present {
+                client.set_if_present(key, value)?
+            } else {
+                client.set_bytes(key, value)?;
+                true
+            };
+            if !done {
+                condition_failed();
+            }
         }
         ClientCmd::Rm { key, addr } => {
             info!("client {} target {}", env!("CARGO_PKG_VERSION"), addr);
             let mut client = KvsClient::connect(addr)?;
             client.remove(key)?;
         }
+        ClientCmd::Cas {
+            key,
+            expected,
+            new,
+            addr,
+        } => {
+            info!("client {} target {}", env!("CARGO_PKG_VERSION"), addr);
+            let mut client = KvsClient::connect(addr)?;
+            let swapped = client.compare_and_swap(
+                key.into_bytes(),
+                expected.map(String::into_bytes),
+                new.map(String::into_bytes),
+            )?;
+            if !swapped {
+                condition_failed();
+            }
+        }
     }
 
     Ok(())
 }
+
+/// a conditional write did not take place
+fn condition_failed() -> ! {
+    eprintln!("Condition not met");
+    process::exit(1);
+}
@@ -3,11 +3,21 @@
 use std::fs;
 use std::io::{Read, Write};
 use std::net::SocketAddr;
+use std::time::Duration;
 
-use log::info;
+use log::{error, info};
 use structopt::StructOpt;
 
-use kvs::{utils, Error, ErrorKind, KvStore, KvsEngine, KvsServer, Result};
+use kvs::{
+    utils, Error, ErrorKind, KvStore, KvsEngine, KvsServer, NaiveThreadPool, Protocol, Result,
+    SharedQueueThreadPool, SledKvsEngine, ThreadPool,
+};
+
+/// opens the engine in the current directory and serves it
+type Start = fn(&ServerOpt) -> Result<()>;
+
+/// engine backends the server can be started with, keyed by name
+const ENGINES: &[(&str, Start)] = &[("kvs", start::<KvStore>), ("sled", start::<SledKvsEngine>)];
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "kvs-server", about = "A command-line key-value store server")]
@@ -24,9 +34,55 @@ struct ServerOpt {
     /// IP:PORT
     #[structopt(short, long, default_value = "127.0.0.1:4000")]
     addr: SocketAddr,
+    /// Thread pool
+    #[structopt(
+        short,
+        long,
+        default_value = "shared",
+        possible_values = &["naive", "shared"],
+        parse(try_from_str = pool)
+    )]
+    pool: Pool,
+    /// Number of threads serving connections
+    #[structopt(short, long, default_value = "4")]
+    threads: u32,
+    /// Seconds after which an idle connection is closed
+    #[structopt(long, default_value = "60")]
+    idle_timeout: u64,
+    /// Wire protocol
+    #[structopt(
+        long,
+        default_value = "kvs",
+        possible_values = &["kvs", "resp"],
+        parse(try_from_str = protocol)
+    )]
+    protocol: Protocol,
+}
+
+/// thread pools connections can be served on
+#[derive(Debug, Clone, Copy)]
+enum Pool {
+    Naive,
+    Shared,
+}
+
+fn pool(name: &str) -> std::result::Result<Pool, String> {
+    match name {
+        "naive" => Ok(Pool::Naive),
+        "shared" => Ok(Pool::Shared),
+        _ => Err(format!("unknown thread pool {}", name)),
+    }
+}
+
+fn protocol(name: &str) -> std::result::Result<Protocol, String> {
+    match name {
+        "kvs" => Ok(Protocol::Kvs),
+        "resp" => Ok(Protocol::Resp),
+        _ => Err(format!("unknown protocol {}", name)),
+    }
 }
 
-fn check(old: String) -> Result<()> {
+fn check(old: &str) -> Result<()> {
     let mut new = String::new();
     if fs::File::open("engine")
         .and_then(|mut file| file.read_to_string(&mut new))
@@ -49,10 +105,36 @@ fn main() -> Result<()> {
         opt.engine.to_string(),
         opt.addr,
     );
-    check(opt.engine)?;
-    let store = KvStore::open(".")?;
-    let mut serve = KvsServer::listen(store, opt.addr)?;
-    serve.serve()?;
+    let start = match ENGINES.iter().find(|(name, _)| *name == opt.engine) {
+        Some((_, start)) => start,
+        None => {
+            let names: Vec<_> = ENGINES.iter().map(|(name, _)| *name).collect();
+            error!(
+                "unknown engine {}, expected one of: {}",
+                opt.engine,
+                names.join(", ")
+            );
+            return Err(Error::from(ErrorKind::InvalidEngine));
+        }
+    };
+    if opt.idle_timeout == 0 {
+        return Err(Error::from(ErrorKind::InvalidCommand));
+    }
+    check(&opt.engine)?;
+    start(&opt)
+}
 
-    Ok(())
+fn start<E: KvsEngine>(opt: &ServerOpt) -> Result<()> {
+    let engine = E::open(".")?;
+    match opt.pool {
+        Pool::Naive => run(engine, NaiveThreadPool::new(opt.threads)?, opt),
+        Pool::Shared => run(engine, SharedQueueThreadPool::new(opt.threads)?, opt),
+    }
+}
+
+fn run(engine: impl KvsEngine, pool: impl ThreadPool, opt: &ServerOpt) -> Result<()> {
+    let mut serve = KvsServer::listen(engine, pool, opt.addr)?;
+    serve.set_idle_timeout(Duration::from_secs(opt.idle_timeout));
+    serve.set_protocol(opt.protocol);
+    serve.serve()
 }
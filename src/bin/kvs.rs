@@ -1,3 +1,5 @@
+use std::ops::Bound;
+
 use structopt::StructOpt;
 
 use kvs::{ErrorKind, KvStore, KvsEngine, Result};
@@ -34,11 +36,23 @@ enum Cmd {
         #[structopt(name = "KEY")]
         key: String,
     },
+    /// List keys and values in ascending key order
+    Scan {
+        /// Only list keys starting with the prefix
+        #[structopt(name = "PREFIX")]
+        prefix: Option<String>,
+        /// First key to list
+        #[structopt(long, conflicts_with = "PREFIX")]
+        start: Option<String>,
+        /// List keys before this one
+        #[structopt(long, conflicts_with = "PREFIX")]
+        end: Option<String>,
+    },
 }
 
 fn main() -> Result<()> {
     let opt = Opt::from_args();
-    let mut store = KvStore::open(".")?;
+    let store = KvStore::open(".")?;
     match opt.cmd {
         Cmd::Get { key } => {
             let out = store
@@ -55,6 +69,30 @@ fn main() -> Result<()> {
                 return Err(e);
             }
         }
+        Cmd::Scan { prefix, start, end } => {
+            let scan = match prefix {
+                Some(prefix) => store.scan_prefix(prefix.into_bytes())?,
+                None => {
+                    let start = match start {
+                        Some(start) => Bound::Included(start.into_bytes()),
+                        None => Bound::Unbounded,
+                    };
+                    let end = match end {
+                        Some(end) => Bound::Excluded(end.into_bytes()),
+                        None => Bound::Unbounded,
+                    };
+                    store.scan((start, end))?
+                }
+            };
+            for pair in scan {
+                let (key, value) = pair?;
+                println!(
+                    "{}\t{}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                );
+            }
+        }
     }
     Ok(())
 }
@@ -1,11 +1,14 @@
 use std::fmt::Display;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::io::{BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
 use std::{io, str};
 
 use structopt::StructOpt;
 
-use kvs::{Resp, Result};
+use kvs::{
+    Error, ErrorKind, KvStore, KvsEngine, KvsServer, Protocol, Resp, Result, SharedQueueThreadPool,
+    ThreadPool,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "kvs", about = "A command-line key-value store client")]
@@ -44,13 +47,19 @@ fn main() -> Result<()> {
 }
 
 fn server(addr: impl ToSocketAddrs) -> Result<()> {
-    let _listener = TcpListener::bind(addr)?;
-    unimplemented!()
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::from(ErrorKind::InvalidCommand))?;
+    let store = KvStore::open(".")?;
+    let mut server = KvsServer::listen(store, SharedQueueThreadPool::new(4)?, addr)?;
+    server.set_protocol(Protocol::Resp);
+    server.serve()
 }
 
 fn client(addr: impl ToSocketAddrs + Display) -> Result<()> {
     let mut stream = TcpStream::connect(&addr)?;
-    let mut recv = [0u8; 512];
+    let mut reader = BufReader::new(stream.try_clone()?);
     loop {
         let mut line = String::new();
         io::stdout().write_all(b"client> ")?;
@@ -69,11 +78,8 @@ fn client(addr: impl ToSocketAddrs + Display) -> Result<()> {
             continue;
         }
         stream.write_all(&Resp::Array(args).ser()?)?;
-        let len = stream.read(&mut recv)?;
-        // if let Ok(s) = str::from_utf8(&recv[0..len]) {
-        if let Ok(s) = Resp::de(&recv[0..len]) {
-            println!("{}> {:?}", addr, s);
-        }
+        let reply = Resp::read_from(&mut reader)?;
+        println!("{}> {:?}", addr, reply);
     }
     Ok(())
 }
@@ -1,2 +1,31 @@
+use std::time::Duration;
+
 pub(crate) const LOG_FILE_EXT: &str = "kvs";
 pub(crate) const HINT_FILE_EXT: &str = "hint";
+pub(crate) const MANIFEST_FILE: &str = "MANIFEST";
+pub(crate) const MANIFEST_TMP_FILE: &str = "MANIFEST.tmp";
+/// version of the on disk format recorded in the manifest,
+/// directories written before the manifest existed are version 0
+pub(crate) const FORMAT_VERSION: u32 = 1;
+/// how often expired keys are looked for and removed in the background
+pub(crate) const EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+/// expired keys removed at once, the writer lock is released in between
+pub(crate) const EXPIRE_BATCH: usize = 128;
+/// connections without any request for this long are closed by the server
+pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// pipelined requests sent before the client stops to read the replies,
+/// bounded so neither side blocks on a full socket buffer while the other does too
+pub(crate) const PIPELINE_BATCH: usize = 128;
+/// version of the framed protocol spoken by `KvsClient` and `KvsServer`,
+/// peers speaking any other version are turned away in the handshake
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
+/// largest frame accepted from the peer
+pub(crate) const MAX_FRAME_LEN: u32 = 512 * 1024 * 1024;
+/// largest frame accepted during the handshake, which is all an unknown peer gets to send
+pub(crate) const MAX_HANDSHAKE_FRAME_LEN: u32 = 4096;
+/// pairs read at once while scanning, the index is not locked in between
+pub(crate) const SCAN_BATCH: usize = 128;
+/// attempts at a transaction built by the server before giving up on conflicts
+pub(crate) const TXN_ATTEMPTS: usize = 16;
+/// largest page of pairs returned for a scan request
+pub(crate) const MAX_SCAN_COUNT: u32 = 1024;
@@ -42,16 +42,97 @@ pub enum ErrorKind {
     Sled,
     /// encoding error
     Encoding,
+    /// missing or unsupported manifest
+    InvalidManifest,
+    /// malformed frame or failed handshake between client and server
+    Protocol,
+    /// error reported by a server with a code this client does not know
+    Server,
+    /// a key read by a transaction changed before it was committed
+    Conflict,
 }
 
 impl Error {
+    /// create an error of the given kind with an underlying cause
+    pub(crate) fn new(
+        kind: ErrorKind,
+        error: impl Into<Box<dyn error::Error + Send + Sync>>,
+    ) -> Self {
+        Error {
+            kind,
+            error: Some(error.into()),
+        }
+    }
+
     /// get the underlying error type
     pub fn kind(&self) -> &ErrorKind {
         &self.kind
     }
+
+    /// the code of the kind and a description of the cause, as sent to clients
+    pub(crate) fn to_wire(&self) -> (u32, String) {
+        let message = match self.error {
+            Some(ref e) => e.to_string(),
+            None => String::new(),
+        };
+        (self.kind.code(), message)
+    }
+
+    /// the error described by `Error::to_wire` on the server
+    pub(crate) fn from_wire(code: u32, message: String) -> Self {
+        let kind = ErrorKind::from_code(code);
+        if message.is_empty() {
+            Error::from(kind)
+        } else {
+            Error::new(kind, message)
+        }
+    }
 }
 
 impl ErrorKind {
+    /// stable code of the kind on the wire, codes must never be reused
+    fn code(&self) -> u32 {
+        match self {
+            ErrorKind::Io => 1,
+            ErrorKind::Serde => 2,
+            ErrorKind::InvalidHintFile => 3,
+            ErrorKind::InvalidLogEntry => 4,
+            ErrorKind::InvalidLogPointer => 5,
+            ErrorKind::KeyNotExist => 6,
+            ErrorKind::InvalidEngine => 7,
+            ErrorKind::InvalidResp => 8,
+            ErrorKind::Logger => 9,
+            ErrorKind::InvalidCommand => 10,
+            ErrorKind::Sled => 11,
+            ErrorKind::Encoding => 12,
+            ErrorKind::InvalidManifest => 13,
+            ErrorKind::Protocol => 14,
+            ErrorKind::Conflict => 15,
+            ErrorKind::Server => 0,
+        }
+    }
+
+    fn from_code(code: u32) -> ErrorKind {
+        match code {
+            1 => ErrorKind::Io,
+            2 => ErrorKind::Serde,
+            3 => ErrorKind::InvalidHintFile,
+            4 => ErrorKind::InvalidLogEntry,
+            5 => ErrorKind::InvalidLogPointer,
+            6 => ErrorKind::KeyNotExist,
+            7 => ErrorKind::InvalidEngine,
+            8 => ErrorKind::InvalidResp,
+            9 => ErrorKind::Logger,
+            10 => ErrorKind::InvalidCommand,
+            11 => ErrorKind::Sled,
+            12 => ErrorKind::Encoding,
+            13 => ErrorKind::InvalidManifest,
+            14 => ErrorKind::Protocol,
+            15 => ErrorKind::Conflict,
+            _ => ErrorKind::Server,
+        }
+    }
+
     fn as_str(&self) -> &str {
         match self {
             ErrorKind::Io => "I/O error",
@@ -66,6 +147,10 @@ impl ErrorKind {
             ErrorKind::InvalidCommand => "invalid command",
             ErrorKind::Sled => "error originated from sled backend",
             ErrorKind::Encoding => "encoding error",
+            ErrorKind::InvalidManifest => "invalid manifest",
+            ErrorKind::Protocol => "wire protocol error",
+            ErrorKind::Server => "server error",
+            ErrorKind::Conflict => "transaction conflict",
         }
     }
 }
@@ -90,6 +175,18 @@ impl error::Error for Error {
     }
 }
 
+impl serde::ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::new(ErrorKind::Serde, msg.to_string())
+    }
+}
+
+impl serde::de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::new(ErrorKind::Serde, msg.to_string())
+    }
+}
+
 impl From<ErrorKind> for Error {
     fn from(kind: ErrorKind) -> Self {
         Error { kind, error: None }
@@ -0,0 +1,72 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// sets and removes applied together by `KvsEngine::write_batch`
+///
+/// Either all of them take effect or none, also across a crash.
+/// Operations are applied in the order they were added.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<Op>,
+}
+
+/// a single operation of a batch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Op {
+    Set(Vec<u8>, Vec<u8>),
+    Rm(Vec<u8>),
+    /// a value living for the given number of milliseconds from when the batch is applied
+    SetWithTtl(Vec<u8>, Vec<u8>, u64),
+}
+
+impl WriteBatch {
+    /// create an empty batch
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// set the value of a key
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(Op::Set(key.into(), value.into()));
+        self
+    }
+
+    /// set the value of a key which expires once the time to live has passed,
+    /// counted from when the batch is applied
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> &mut Self {
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        self.ops.push(Op::SetWithTtl(key.into(), value.into(), ttl));
+        self
+    }
+
+    /// remove a key, the whole batch fails if it does not exist at this point of the batch
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(Op::Rm(key.into()));
+        self
+    }
+
+    /// number of operations in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// whether the batch has no operations
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<Op> {
+        self.ops
+    }
+}
@@ -1,59 +1,333 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::{BufReader, BufWriter, Write};
+use std::mem;
 use std::net::{SocketAddr, TcpStream};
+use std::ops::RangeBounds;
+use std::time::Duration;
 
 use log::{error, info};
 
-use crate::{utils, Error, ErrorKind, Result};
+use crate::config::{MAX_FRAME_LEN, PIPELINE_BATCH, SCAN_BATCH};
+use crate::{utils, Error, ErrorKind, Result, Transaction, WriteBatch};
+
+use super::wire;
 
 /// kvs client
+///
+/// The connection is kept open, so a client can be reused for any number of requests.
 pub struct KvsClient {
-    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+/// pages through the pairs of a range, created by `KvsClient::scan`
+struct ClientScan<'a> {
+    client: &'a mut KvsClient,
+    /// start of the next page, `None` once the range is exhausted
+    next: Option<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    page: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+/// requests queued to be sent at once, created by `KvsClient::pipeline`
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<utils::Request>,
 }
 
 impl KvsClient {
-    /// connect to the given socket address
+    /// connect to the given socket address and make sure the server speaks our protocol version
     pub fn connect(addr: SocketAddr) -> Result<Self> {
         let stream = TcpStream::connect(addr)?;
         info!("connected to {}", stream.peer_addr()?);
-        Ok(Self { stream })
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        wire::hello(&mut reader, &mut writer)?;
+        Ok(Self { reader, writer })
     }
 
     /// get key
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        expect_value(self.request(&utils::Request::Get(key))?)
+    }
+
+    /// set key
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        expect_value(self.request(&utils::Request::Set(key, value))?).map(|_| ())
+    }
+
+    /// remove key
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        expect_value(self.request(&utils::Request::Rm(key))?).map(|_| ())
+    }
+
+    /// set the key to `new` if its value is still `expected`, `None` meaning absent,
+    /// return whether the value was swapped
+    pub fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        expect_swapped(self.request(&utils::Request::Cas { key, expected, new })?)
+    }
+
+    /// set a key which does not exist yet, return whether it was set
+    pub fn set_if_absent(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        expect_swapped(self.request(&utils::Request::SetIfAbsent(key, value))?)
+    }
+
+    /// set a key only if it exists, return whether it was set
+    pub fn set_if_present(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        expect_swapped(self.request(&utils::Request::SetIfPresent(key, value))?)
+    }
+
+    /// get the value of a key along with its version, 0 if the key does not exist
+    pub fn get_versioned(&mut self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        match self.request(&utils::Request::GetVersioned(key))? {
+            utils::Respond::Versioned { value, version } => Ok((value, version)),
+            res => Err(Error::new(
+                ErrorKind::Protocol,
+                format!("unexpected respond {:?}", res),
+            )),
+        }
+    }
+
+    /// get the value of a key and watch it in the transaction
+    pub fn get_watched(&mut self, txn: &mut Transaction, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let (value, version) = self.get_versioned(key.clone())?;
+        txn.watch(key, version);
+        Ok(value)
+    }
+
+    /// apply the writes of the transaction unless a watched key changed,
+    /// which is reported as an error of kind `Conflict`
+    pub fn commit(&mut self, txn: Transaction) -> Result<()> {
+        expect_value(self.request(&utils::Request::Commit(txn))?).map(|_| ())
+    }
+
+    /// set a key which expires once the time to live has passed
+    pub fn set_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let req = utils::Request::SetWithTtl(key, value, millis(ttl));
+        expect_value(self.request(&req)?).map(|_| ())
+    }
+
+    /// make an existing key expire once the time to live has passed,
+    /// return whether the key exists
+    pub fn expire(&mut self, key: Vec<u8>, ttl: Duration) -> Result<bool> {
+        expect_swapped(self.request(&utils::Request::Expire(key, millis(ttl)))?)
+    }
+
+    /// get the time a key has left to live, `None` if it does not expire,
+    /// fails with `ErrorKind::KeyNotExist` if there is no such key
+    pub fn ttl(&mut self, key: Vec<u8>) -> Result<Option<Duration>> {
+        match self.request(&utils::Request::Ttl(key))? {
+            utils::Respond::Ttl(ttl) => Ok(ttl.map(Duration::from_millis)),
+            res => Err(Error::new(
+                ErrorKind::Protocol,
+                format!("unexpected respond {:?}", res),
+            )),
+        }
+    }
+
+    /// keep a key from expiring, return whether it had a time to live
+    pub fn persist(&mut self, key: Vec<u8>) -> Result<bool> {
+        expect_swapped(self.request(&utils::Request::Persist(key))?)
+    }
+
+    /// apply all operations of the batch atomically
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        expect_value(self.request(&utils::Request::Batch(batch))?).map(|_| ())
+    }
+
+    /// get string key, fails with `ErrorKind::Encoding` if the value is not UTF-8
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        bincode::serialize_into(&self.stream, &utils::Request::Get(key))?;
-        let res: utils::Respond = bincode::deserialize_from(&self.stream)?;
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// set string key
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// remove string key
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// queue several requests and send them without waiting for each reply
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    /// iterate over the pairs whose key lies in the range, in ascending key order
+    ///
+    /// Pairs are fetched from the server a page at a time, the iterator ends after an error.
+    pub fn scan(
+        &mut self,
+        range: impl RangeBounds<Vec<u8>>,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        let (start, end) = utils::half_open(&range);
+        ClientScan {
+            client: self,
+            next: Some(start),
+            end,
+            page: VecDeque::new(),
+        }
+    }
+
+    /// iterate over the pairs whose key starts with the prefix, in ascending key order
+    pub fn scan_prefix(
+        &mut self,
+        prefix: Vec<u8>,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        let end = utils::prefix_end(&prefix);
+        ClientScan {
+            client: self,
+            next: Some(prefix),
+            end,
+            page: VecDeque::new(),
+        }
+    }
+
+    fn request(&mut self, req: &utils::Request) -> Result<utils::Respond> {
+        self.send(req)?;
+        self.writer.flush()?;
+        self.recv()?
+    }
+
+    fn send(&mut self, req: &utils::Request) -> Result<()> {
+        wire::write_frame(&mut self.writer, req)
+    }
+
+    /// the outer error is about the connection, the inner one is reported by the server
+    fn recv(&mut self) -> Result<Result<utils::Respond>> {
+        let res: utils::Respond =
+            wire::decode(&wire::read_frame(&mut self.reader, MAX_FRAME_LEN)?)?;
         info!("received respond {:?}", res);
-        match res {
-            utils::Respond::Ok(v) => Ok(v),
-            utils::Respond::Err(e) => {
+        Ok(match res {
+            utils::Respond::Error { code, message } => {
+                let e = Error::from_wire(code, message);
                 error!("server responded with an error {}", e);
-                Err(Error::from(ErrorKind::InvalidCommand))
+                Err(e)
             }
+            res => Ok(res),
+        })
+    }
+}
+
+/// the value carried by the respond to a get, set or remove request
+fn expect_value(res: utils::Respond) -> Result<Option<Vec<u8>>> {
+    match res {
+        utils::Respond::Ok(v) => Ok(v),
+        res => Err(Error::new(
+            ErrorKind::Protocol,
+            format!("unexpected respond {:?}", res),
+        )),
+    }
+}
+
+/// a time to live as sent over the wire
+fn millis(ttl: Duration) -> u64 {
+    u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// the outcome carried by the respond to a conditional write
+fn expect_swapped(res: utils::Respond) -> Result<bool> {
+    match res {
+        utils::Respond::Swapped(swapped) => Ok(swapped),
+        res => Err(Error::new(
+            ErrorKind::Protocol,
+            format!("unexpected respond {:?}", res),
+        )),
+    }
+}
+
+impl<'a> ClientScan<'a> {
+    fn fetch(&mut self, start: Vec<u8>) -> Result<()> {
+        let req = utils::Request::Scan {
+            start,
+            end: self.end.clone(),
+            count: SCAN_BATCH as u32,
+        };
+        match self.client.request(&req)? {
+            utils::Respond::Scan { pairs, next } => {
+                self.page.extend(pairs);
+                self.next = next;
+                Ok(())
+            }
+            res => Err(Error::new(
+                ErrorKind::Protocol,
+                format!("unexpected respond {:?}", res),
+            )),
         }
     }
+}
 
-    /// set key
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        bincode::serialize_into(&self.stream, &utils::Request::Set(key, value))?;
-        let res: utils::Respond = bincode::deserialize_from(&self.stream)?;
-        match res {
-            utils::Respond::Ok(_) => Ok(()),
-            utils::Respond::Err(e) => {
-                error!("server responded with an error {}", e);
-                Err(Error::from(ErrorKind::InvalidCommand))
+impl<'a> Iterator for ClientScan<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.page.is_empty() {
+            let start = self.next.take()?;
+            if let Err(e) = self.fetch(start) {
+                return Some(Err(e));
             }
         }
+        self.page.pop_front().map(Ok)
     }
+}
 
-    /// remove key
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        bincode::serialize_into(&self.stream, &utils::Request::Rm(key))?;
-        let res: utils::Respond = bincode::deserialize_from(&self.stream)?;
-        match res {
-            utils::Respond::Ok(_) => Ok(()),
-            utils::Respond::Err(e) => {
-                error!("server responded with an error {}", e);
-                Err(Error::from(ErrorKind::InvalidCommand))
+impl<'a> Pipeline<'a> {
+    /// queue getting a key
+    pub fn get(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.requests.push(utils::Request::Get(key.into()));
+        self
+    }
+
+    /// queue setting a key
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.requests
+            .push(utils::Request::Set(key.into(), value.into()));
+        self
+    }
+
+    /// queue removing a key
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.requests.push(utils::Request::Rm(key.into()));
+        self
+    }
+
+    /// send the queued requests and return their results in the same order
+    ///
+    /// Each request succeeds or fails on its own, the value of a successful `get`
+    /// is `Some` if the key exists and the other requests give `None`.
+    /// The outer error means the connection broke and the client should not be used anymore,
+    /// except for a request too large to send, which fails the pipeline before any of it is sent.
+    /// The queue is empty afterwards.
+    pub fn execute(&mut self) -> Result<Vec<Result<Option<Vec<u8>>>>> {
+        // encode everything first so a bad request leaves nothing buffered on the connection
+        let frames = mem::take(&mut self.requests)
+            .iter()
+            .map(wire::encode_frame)
+            .collect::<Result<Vec<_>>>()?;
+        let mut results = Vec::with_capacity(frames.len());
+        for batch in frames.chunks(PIPELINE_BATCH) {
+            for frame in batch {
+                self.client.writer.write_all(frame)?;
+            }
+            self.client.writer.flush()?;
+            for _ in batch {
+                results.push(self.client.recv()?.and_then(expect_value));
             }
         }
+        Ok(results)
     }
 }
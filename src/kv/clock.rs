@@ -0,0 +1,68 @@
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of the time expiries are judged by
+///
+/// The system clock by default. A manual clock stands still until it is advanced,
+/// so keys can be made to expire without waiting for them, clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct Clock {
+    /// unix time in milliseconds set by hand, the system time if `None`
+    manual: Option<Arc<AtomicU64>>,
+}
+
+impl Clock {
+    /// A clock following the system time.
+    pub fn system() -> Self {
+        Self::default()
+    }
+
+    /// A clock standing still at the current system time until it is advanced.
+    #[cfg(feature = "testing")]
+    pub fn manual() -> Self {
+        Self {
+            manual: Some(Arc::new(AtomicU64::new(system_millis()))),
+        }
+    }
+
+    /// Move a manual clock forward, the system clock is left alone.
+    #[cfg(feature = "testing")]
+    pub fn advance(&self, by: Duration) {
+        if let Some(now) = &self.manual {
+            now.fetch_add(millis(by), Ordering::SeqCst);
+        }
+    }
+
+    /// The current unix time in milliseconds.
+    pub fn now_millis(&self) -> u64 {
+        match &self.manual {
+            Some(now) => now.load(Ordering::SeqCst),
+            None => system_millis(),
+        }
+    }
+
+    /// the unix time in milliseconds at which something living for `ttl` from now expires
+    pub(crate) fn deadline(&self, ttl: Duration) -> u64 {
+        self.now_millis().saturating_add(millis(ttl))
+    }
+
+    /// whether the expiry, if there is one, has passed
+    pub(crate) fn is_expired(&self, expires: Option<u64>) -> bool {
+        match expires {
+            Some(expires) => expires <= self.now_millis(),
+            None => false,
+        }
+    }
+}
+
+fn system_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
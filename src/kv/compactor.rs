@@ -0,0 +1,336 @@
+use std::fs;
+use std::io::{BufReader, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+
+use log::{error, info};
+
+use super::clock::Clock;
+use super::store::{KvStoreOptions, MemTable, Usage};
+use crate::log::{Entry, Hint, Manifest, Pointer, Segment};
+use crate::Result;
+
+/// handle to the background compaction thread
+///
+/// The thread only writes new segments, swapping them in and deleting the inputs
+/// is left to the writer, so files never vanish from under other users of the directory.
+#[derive(Debug)]
+pub(crate) struct Compactor {
+    sender: Option<Sender<Job>>,
+    handle: Option<JoinHandle<()>>,
+    /// set from scheduling a job until its outputs are installed or it failed
+    busy: Arc<AtomicBool>,
+    /// outputs of a finished job waiting to be installed,
+    /// notified once a job finished or failed
+    done: Arc<(Mutex<Option<Done>>, Condvar)>,
+    memtbl: Arc<RwLock<MemTable>>,
+    manifest: Arc<Mutex<Manifest>>,
+}
+
+/// rewrite the live entries of `inputs` into segments named after `output`
+#[derive(Debug)]
+struct Job {
+    /// segments to compact in replay order
+    inputs: Vec<PathBuf>,
+    /// number of inputs older than every segment not being compacted,
+    /// tombstones in the remaining inputs must be kept as older segments may still hold the keys
+    tombstone_free: usize,
+    output: PathBuf,
+    /// the output is placed right before this segment in the manifest
+    before: PathBuf,
+}
+
+/// a job whose outputs are written and sealed
+#[derive(Debug)]
+struct Done {
+    inputs: Vec<PathBuf>,
+    /// sealed outputs and their size
+    outputs: Vec<(PathBuf, u64)>,
+    before: PathBuf,
+    /// keys copied to the outputs, with the pointers they had and got,
+    /// `None` if they expired
+    moved: Vec<(Vec<u8>, Pointer, Option<Pointer>)>,
+}
+
+/// segments produced by a compaction job, rotated by size
+struct Output {
+    name: PathBuf,
+    segment_size: u64,
+    active: Option<Segment>,
+    sealed: Vec<(PathBuf, u64)>,
+}
+
+impl Compactor {
+    pub fn new(
+        memtbl: Arc<RwLock<MemTable>>,
+        manifest: Arc<Mutex<Manifest>>,
+        options: KvStoreOptions,
+        time: Clock,
+    ) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let busy = Arc::new(AtomicBool::new(false));
+        let done = Arc::new((Mutex::new(None), Condvar::new()));
+        let handle = {
+            let (busy, done, memtbl) = (Arc::clone(&busy), Arc::clone(&done), Arc::clone(&memtbl));
+            thread::Builder::new()
+                .name("kvs-compactor".to_owned())
+                .spawn(move || {
+                    for job in receiver {
+                        let (lock, finished) = &*done;
+                        match job.run(&memtbl, &options, &time) {
+                            Ok(outputs) => *lock.lock().unwrap() = Some(outputs),
+                            Err(e) => {
+                                error!("compaction failed: {}", e);
+                                let _lock = lock.lock().unwrap();
+                                busy.store(false, Ordering::SeqCst);
+                            }
+                        }
+                        finished.notify_all();
+                    }
+                })?
+        };
+        Ok(Self {
+            sender: Some(sender),
+            handle: Some(handle),
+            busy,
+            done,
+            memtbl,
+            manifest,
+        })
+    }
+
+    /// whether a compaction job is still running
+    pub fn is_busy(&self) -> bool {
+        self.busy.load(Ordering::SeqCst)
+    }
+
+    /// schedule the compaction of sealed segments
+    pub fn compact(
+        &self,
+        inputs: Vec<PathBuf>,
+        tombstone_free: usize,
+        output: PathBuf,
+        before: PathBuf,
+    ) {
+        self.busy.store(true, Ordering::SeqCst);
+        let job = Job {
+            inputs,
+            tombstone_free,
+            output,
+            before,
+        };
+        if let Some(sender) = &self.sender {
+            if sender.send(job).is_err() {
+                error!("compaction thread is gone");
+                self.busy.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// swap in the outputs of a finished job and delete its inputs,
+    /// called by the writer with the writer lock held
+    pub fn install(&self) {
+        let done = match self.done.0.lock().unwrap().take() {
+            Some(done) => done,
+            None => return,
+        };
+        if let Err(e) = done.install(&self.memtbl, &self.manifest) {
+            error!("compaction failed: {}", e);
+        }
+        self.busy.store(false, Ordering::SeqCst);
+    }
+
+    /// wait for the running job to finish, its outputs are left to `install`
+    #[cfg(feature = "testing")]
+    pub fn wait(&self) {
+        let (lock, finished) = &*self.done;
+        let mut done = lock.lock().unwrap();
+        while done.is_none() && self.is_busy() {
+            done = finished.wait(done).unwrap();
+        }
+    }
+
+    /// wait for the running job to finish and stop the thread
+    pub fn stop(&mut self) {
+        // closing the channel stops the thread once the running job is done
+        drop(self.sender.take());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("compaction thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Job {
+    /// copy the live entries of the inputs into new segments
+    fn run(
+        self,
+        memtbl: &RwLock<MemTable>,
+        options: &KvStoreOptions,
+        time: &Clock,
+    ) -> Result<Done> {
+        info!("compacting {} segments", self.inputs.len());
+        let mut output = Output::new(self.output, options.segment_size);
+        let mut moved = Vec::new();
+        for (i, input) in self.inputs.iter().enumerate() {
+            let hint = Hint::open(input, false)?;
+            let mut reader = BufReader::new(fs::File::open(input)?);
+            for (key, &(offset, len)) in hint.offset() {
+                // skip entries which have been overwritten or removed since
+                let old = Pointer::new(input, offset, len);
+                if memtbl
+                    .read()
+                    .unwrap()
+                    .map
+                    .get(key)
+                    .map(|slot| &slot.pointer)
+                    != Some(&old)
+                {
+                    continue;
+                }
+                reader.seek(SeekFrom::Start(offset))?;
+                match Entry::read_from(&mut reader)? {
+                    Entry::Set(key, value) => {
+                        let new = output.set(key.clone(), value, None)?;
+                        moved.push((key, old, Some(new)));
+                    }
+                    // expired values are dropped, older segments may still hold the key
+                    Entry::SetEx(key, _, expires) if time.is_expired(Some(expires)) => {
+                        if i >= self.tombstone_free {
+                            output.remove(&key)?;
+                        }
+                        moved.push((key, old, None));
+                    }
+                    Entry::SetEx(key, value, expires) => {
+                        let new = output.set(key.clone(), value, Some(expires))?;
+                        moved.push((key, old, Some(new)));
+                    }
+                    _ => {}
+                }
+            }
+            if i >= self.tombstone_free {
+                for key in hint.count().keys() {
+                    if !hint.offset().contains_key(key)
+                        && !memtbl.read().unwrap().map.contains_key(key)
+                    {
+                        output.remove(key)?;
+                    }
+                }
+            }
+        }
+        output.finish()?;
+        Ok(Done {
+            inputs: self.inputs,
+            outputs: output.sealed,
+            before: self.before,
+            moved,
+        })
+    }
+}
+
+impl Done {
+    fn install(self, memtbl: &RwLock<MemTable>, manifest: &Mutex<Manifest>) -> Result<()> {
+        // from now on the inputs are garbage, a crash before they are deleted is harmless
+        let outputs: Vec<_> = self.outputs.iter().map(|(path, _)| path.clone()).collect();
+        manifest
+            .lock()
+            .unwrap()
+            .replace(&self.inputs, &outputs, &self.before)?;
+
+        // keys written to since they were copied keep their newer pointer
+        let unpinned: Vec<_> = {
+            let mut memtbl = memtbl.write().unwrap();
+            for (path, size) in &self.outputs {
+                let usage = Usage {
+                    total: *size,
+                    dead: 0,
+                };
+                memtbl.usage.insert(path.clone(), usage);
+            }
+            for (key, old, new) in self.moved {
+                // the value is the same, so the key keeps its version
+                let current = memtbl.map.get_mut(&key).filter(|slot| slot.pointer == old);
+                match (current, new) {
+                    (Some(slot), Some(new)) => slot.pointer = new,
+                    (Some(_), None) => memtbl.drop_expired(&key),
+                    (None, Some(new)) => memtbl.kill_record(&new),
+                    (None, None) => {}
+                }
+            }
+            for input in &self.inputs {
+                memtbl.usage.remove(input);
+            }
+            // snapshots may still read from inputs, those are deleted once released
+            let (pinned, unpinned) = self
+                .inputs
+                .into_iter()
+                .partition(|input| memtbl.is_pinned(input));
+            memtbl.retire(pinned);
+            unpinned
+        };
+
+        for file in &unpinned {
+            Segment::delete(file)?;
+        }
+        Ok(())
+    }
+}
+
+impl Output {
+    fn new(name: PathBuf, segment_size: u64) -> Self {
+        Self {
+            name,
+            segment_size,
+            active: None,
+            sealed: Vec::new(),
+        }
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires: Option<u64>) -> Result<Pointer> {
+        let pointer = self.segment()?.set_with_expiry(key, value, expires)?;
+        self.rotate()?;
+        Ok(pointer)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.segment()?.remove(key)?;
+        self.rotate()
+    }
+
+    fn segment(&mut self) -> Result<&mut Segment> {
+        if self.active.is_none() {
+            let mut name = self.name.clone().into_os_string();
+            name.push(format!("-{:04}", self.sealed.len()));
+            self.active = Some(Segment::create(name)?);
+        }
+        Ok(self.active.as_mut().unwrap())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        match &self.active {
+            Some(segment) if segment.size() >= self.segment_size => self.finish(),
+            _ => Ok(()),
+        }
+    }
+
+    /// seal the segment being written
+    fn finish(&mut self) -> Result<()> {
+        if let Some(segment) = self.active.take() {
+            let path = segment.path().clone();
+            let size = segment.size();
+            segment.seal()?;
+            self.sealed.push((path, size));
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,11 @@
+pub mod batch;
 pub mod client;
+pub mod clock;
+pub mod compactor;
+pub mod redis;
 pub mod server;
 pub mod sled;
 pub mod store;
+pub mod ticker;
+pub mod transaction;
+pub mod wire;
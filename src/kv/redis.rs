@@ -0,0 +1,689 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::mem;
+use std::time::Duration;
+
+use log::info;
+
+use crate::config::TXN_ATTEMPTS;
+use crate::{Error, ErrorKind, KvsEngine, Resp, Result, Transaction};
+
+/// protocol version used when the client did not say otherwise
+const DEFAULT_VERSION: i64 = 2;
+
+/// state of one RESP connection
+#[derive(Debug)]
+pub(crate) struct Session {
+    /// protocol version negotiated with HELLO, replies are downgraded for RESP2 clients
+    version: i64,
+    /// keys given to WATCH and the versions they had then
+    watched: Vec<(Vec<u8>, u64)>,
+    /// commands queued since MULTI, `None` outside of a transaction
+    queued: Option<Vec<Vec<Vec<u8>>>>,
+    /// a command could not be queued, so EXEC discards the transaction
+    aborted: bool,
+}
+
+/// what key commands operate on, the engine itself or a transaction being built
+trait Keys {
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    /// set the key, which expires after `ttl` if given
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Option<Duration>) -> Result<()>;
+    /// set the key only if whether it exists matches, return whether it was set
+    fn set_if(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        exists: bool,
+        ttl: Option<Duration>,
+    ) -> Result<bool>;
+    /// remove the key, return whether it existed
+    fn remove(&mut self, key: Vec<u8>) -> Result<bool>;
+}
+
+/// commands applied to the engine right away
+struct Direct<'a, T>(&'a T);
+
+/// commands queued by MULTI, their writes are committed together by EXEC
+struct Staged<'a, T> {
+    engine: &'a T,
+    txn: Transaction,
+    /// values written by earlier commands, `None` for removed keys
+    written: HashMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+/// a command understood by the RESP front end
+struct Command {
+    name: &'static str,
+    /// number of arguments including the name, negative means at least that many
+    arity: i64,
+    flags: &'static [&'static str],
+    /// position of the first and last key argument and the step between keys
+    keys: (i64, i64, i64),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "get",
+        arity: 2,
+        flags: &["readonly", "fast"],
+        keys: (1, 1, 1),
+    },
+    Command {
+        name: "set",
+        arity: -3,
+        flags: &["write", "denyoom"],
+        keys: (1, 1, 1),
+    },
+    Command {
+        name: "del",
+        arity: -2,
+        flags: &["write"],
+        keys: (1, -1, 1),
+    },
+    Command {
+        name: "exists",
+        arity: -2,
+        flags: &["readonly", "fast"],
+        keys: (1, -1, 1),
+    },
+    Command {
+        name: "expire",
+        arity: 3,
+        flags: &["write", "fast"],
+        keys: (1, 1, 1),
+    },
+    Command {
+        name: "pexpire",
+        arity: 3,
+        flags: &["write", "fast"],
+        keys: (1, 1, 1),
+    },
+    Command {
+        name: "ttl",
+        arity: 2,
+        flags: &["readonly", "random", "fast"],
+        keys: (1, 1, 1),
+    },
+    Command {
+        name: "pttl",
+        arity: 2,
+        flags: &["readonly", "random", "fast"],
+        keys: (1, 1, 1),
+    },
+    Command {
+        name: "persist",
+        arity: 2,
+        flags: &["write", "fast"],
+        keys: (1, 1, 1),
+    },
+    Command {
+        name: "ping",
+        arity: -1,
+        flags: &["stale", "fast"],
+        keys: (0, 0, 0),
+    },
+    Command {
+        name: "echo",
+        arity: 2,
+        flags: &["fast"],
+        keys: (0, 0, 0),
+    },
+    Command {
+        name: "quit",
+        arity: 1,
+        flags: &["fast"],
+        keys: (0, 0, 0),
+    },
+    Command {
+        name: "hello",
+        arity: -1,
+        flags: &["noscript", "loading", "stale", "fast"],
+        keys: (0, 0, 0),
+    },
+    Command {
+        name: "multi",
+        arity: 1,
+        flags: &["noscript", "loading", "stale", "fast"],
+        keys: (0, 0, 0),
+    },
+    Command {
+        name: "exec",
+        arity: 1,
+        flags: &["noscript", "loading", "stale", "skip_slowlog"],
+        keys: (0, 0, 0),
+    },
+    Command {
+        name: "discard",
+        arity: 1,
+        flags: &["noscript", "loading", "stale", "fast"],
+        keys: (0, 0, 0),
+    },
+    Command {
+        name: "watch",
+        arity: -2,
+        flags: &["noscript", "loading", "stale", "fast"],
+        keys: (1, -1, 1),
+    },
+    Command {
+        name: "unwatch",
+        arity: 1,
+        flags: &["noscript", "loading", "stale", "fast"],
+        keys: (0, 0, 0),
+    },
+    Command {
+        name: "command",
+        arity: -1,
+        flags: &["random", "loading", "stale"],
+        keys: (0, 0, 0),
+    },
+];
+
+/// read one command from the connection and write back the reply
+/// return `false` once the connection should be closed
+pub(crate) fn serve<T: KvsEngine>(
+    engine: &T,
+    session: &mut Session,
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+) -> Result<bool> {
+    let args = match read_command(reader) {
+        Ok(args) => args,
+        // the peer is gone or went idle in the middle of a command, nobody reads a reply
+        Err(e) if matches!(e.kind(), ErrorKind::Io) => {
+            info!("closing connection: {}", e);
+            return Ok(false);
+        }
+        Err(e) => {
+            // there is no way to find the start of the next command
+            writer.write_all(&error(format!("Protocol error: {}", e)).ser()?)?;
+            return Ok(false);
+        }
+    };
+    if args.is_empty() {
+        return Ok(true);
+    }
+    let (mut reply, open) = execute(engine, session, &args);
+    if session.version < 3 {
+        reply = reply.into_resp2();
+    }
+    writer.write_all(&reply.ser()?)?;
+    Ok(open)
+}
+
+/// a command is an array of bulk strings,
+/// anything else is taken as an inline command of space separated words
+fn read_command(reader: &mut impl BufRead) -> Result<Vec<Vec<u8>>> {
+    if reader.fill_buf()?.first() != Some(&b'*') {
+        let line = Resp::read_line(reader)?;
+        return Ok(line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_vec())
+            .collect());
+    }
+    match Resp::read_from(reader)? {
+        Resp::Array(arr) => arr
+            .into_iter()
+            .map(|val| match val {
+                Resp::Bulk(arg) => Ok(arg),
+                _ => Err(ErrorKind::InvalidResp.into()),
+            })
+            .collect(),
+        _ => Err(ErrorKind::InvalidResp.into()),
+    }
+}
+
+fn execute<T: KvsEngine>(engine: &T, session: &mut Session, args: &[Vec<u8>]) -> (Resp, bool) {
+    let name = String::from_utf8_lossy(&args[0]).to_lowercase();
+    info!("incoming RESP command {}", name);
+    let command = match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => command,
+        None => {
+            let reply = error(format!("unknown command '{}'", name));
+            return (session.reject(reply), true);
+        }
+    };
+    let argc = args.len() as i64;
+    if (command.arity > 0 && argc != command.arity) || argc < -command.arity {
+        let msg = format!("wrong number of arguments for '{}' command", name);
+        return (session.reject(error(msg)), true);
+    }
+    // inside MULTI commands are queued until EXEC, those which can not run in a transaction
+    // make EXEC discard it, and only the ones controlling the transaction run right away
+    if let Some(queued) = &mut session.queued {
+        match name.as_str() {
+            "get" | "set" | "del" | "exists" | "ping" | "echo" | "unwatch" => {
+                queued.push(args.to_vec());
+                return (Resp::Simple("QUEUED".to_owned()), true);
+            }
+            "multi" | "exec" | "discard" | "watch" | "quit" => {}
+            _ => {
+                let msg = format!("{} is not supported inside MULTI", name.to_uppercase());
+                return (session.reject(error(msg)), true);
+            }
+        }
+    }
+    let reply = match name.as_str() {
+        "quit" => return (Resp::Simple("OK".to_owned()), false),
+        "ping" | "echo" => Ok(echo(&name, &args[1..])),
+        "hello" => Ok(hello(session, &args[1..])),
+        "command" => command_info(&args[1..]),
+        "multi" | "exec" | "discard" | "watch" | "unwatch" => {
+            transaction(engine, session, &name, &args[1..])
+        }
+        "expire" | "pexpire" | "ttl" | "pttl" | "persist" => expiry(engine, &name, &args[1..]),
+        _ => execute_key(&mut Direct(engine), &name, &args[1..]),
+    };
+    (reply.unwrap_or_else(|e| error(e.to_string())), true)
+}
+
+/// `PING [message]` and `ECHO message`
+fn echo(name: &str, args: &[Vec<u8>]) -> Resp {
+    match (name, args.first()) {
+        (_, Some(msg)) => Resp::Bulk(msg.clone()),
+        ("ping", None) => Resp::Simple("PONG".to_owned()),
+        _ => unreachable!("ECHO takes a message"),
+    }
+}
+
+/// commands operating on keys
+fn execute_key(engine: &mut impl Keys, name: &str, args: &[Vec<u8>]) -> Result<Resp> {
+    let mut keys = args.iter().cloned();
+    match name {
+        "get" => match engine.get(keys.next().unwrap())? {
+            Some(value) => Ok(Resp::Bulk(value)),
+            None => Ok(Resp::Null),
+        },
+        "set" => {
+            let (key, value) = (keys.next().unwrap(), keys.next().unwrap());
+            let (mut condition, mut ttl) = (None, None);
+            while let Some(option) = keys.next() {
+                let option = String::from_utf8_lossy(&option).to_lowercase();
+                match option.as_str() {
+                    "nx" | "xx" if condition.is_none() => condition = Some(option),
+                    "ex" | "px" if ttl.is_none() => {
+                        let unit = if option == "ex" { 1000 } else { 1 };
+                        match keys.next().as_deref().and_then(integer) {
+                            Some(n) if n > 0 && n.checked_mul(unit).is_some() => {
+                                ttl = Some(Duration::from_millis((n * unit) as u64))
+                            }
+                            Some(_) => {
+                                return Ok(error("invalid expire time in 'set' command".to_owned()))
+                            }
+                            None => return Ok(error("syntax error".to_owned())),
+                        }
+                    }
+                    _ => return Ok(error("syntax error".to_owned())),
+                }
+            }
+            let done = match condition.as_deref() {
+                Some("nx") => engine.set_if(key, value, false, ttl)?,
+                Some(_) => engine.set_if(key, value, true, ttl)?,
+                None => {
+                    engine.set(key, value, ttl)?;
+                    true
+                }
+            };
+            match done {
+                true => Ok(Resp::Simple("OK".to_owned())),
+                false => Ok(Resp::Null),
+            }
+        }
+        "del" => {
+            let mut count = 0;
+            for key in keys {
+                if engine.remove(key)? {
+                    count += 1;
+                }
+            }
+            Ok(Resp::Integer(count))
+        }
+        "exists" => {
+            let mut count = 0;
+            for key in keys {
+                if engine.get(key)?.is_some() {
+                    count += 1;
+                }
+            }
+            Ok(Resp::Integer(count))
+        }
+        _ => unreachable!("{} is not a key command", name),
+    }
+}
+
+/// `EXPIRE key seconds`, `PEXPIRE key milliseconds`, `TTL key`, `PTTL key` and `PERSIST key`
+fn expiry<T: KvsEngine>(engine: &T, name: &str, args: &[Vec<u8>]) -> Result<Resp> {
+    let key = args[0].clone();
+    match name {
+        "expire" | "pexpire" => {
+            let unit = if name == "expire" { 1000 } else { 1 };
+            let ttl = match integer(&args[1]) {
+                Some(n) => n.checked_mul(unit),
+                None => return Ok(error("value is not an integer or out of range".to_owned())),
+            };
+            let done = match ttl {
+                Some(ttl) if ttl > 0 => engine.expire(key, Duration::from_millis(ttl as u64))?,
+                // a time to live in the past removes the key right away
+                Some(_) => Direct(engine).remove(key)?,
+                None => return Ok(error(format!("invalid expire time in '{}' command", name))),
+            };
+            Ok(Resp::Integer(done as i64))
+        }
+        "ttl" | "pttl" => match engine.ttl(key) {
+            Ok(Some(ttl)) if name == "ttl" => {
+                Ok(Resp::Integer(((ttl.as_millis() + 500) / 1000) as i64))
+            }
+            Ok(Some(ttl)) => Ok(Resp::Integer(ttl.as_millis() as i64)),
+            Ok(None) => Ok(Resp::Integer(-1)),
+            Err(e) if matches!(e.kind(), ErrorKind::KeyNotExist) => Ok(Resp::Integer(-2)),
+            Err(e) => Err(e),
+        },
+        "persist" => Ok(Resp::Integer(engine.persist(key)? as i64)),
+        _ => unreachable!("{} is not an expiry command", name),
+    }
+}
+
+/// `MULTI`, `EXEC`, `DISCARD`, `WATCH key...` and `UNWATCH`
+fn transaction<T: KvsEngine>(
+    engine: &T,
+    session: &mut Session,
+    name: &str,
+    args: &[Vec<u8>],
+) -> Result<Resp> {
+    let ok = Resp::Simple("OK".to_owned());
+    match name {
+        "multi" if session.queued.is_some() => {
+            Ok(error("MULTI calls can not be nested".to_owned()))
+        }
+        "multi" => {
+            session.queued = Some(Vec::new());
+            Ok(ok)
+        }
+        "watch" if session.queued.is_some() => {
+            Ok(error("WATCH inside MULTI is not allowed".to_owned()))
+        }
+        "watch" => {
+            for key in args {
+                if !session.watched.iter().any(|(watched, _)| watched == key) {
+                    let (_, version) = engine.get_versioned(key.clone())?;
+                    session.watched.push((key.clone(), version));
+                }
+            }
+            Ok(ok)
+        }
+        "unwatch" => {
+            session.watched.clear();
+            Ok(ok)
+        }
+        _ => {
+            // EXEC and DISCARD end the transaction and forget about the watched keys
+            let queued = match session.queued.take() {
+                Some(queued) => queued,
+                None => return Ok(error(format!("{} without MULTI", name.to_uppercase()))),
+            };
+            let watched = mem::take(&mut session.watched);
+            let aborted = mem::replace(&mut session.aborted, false);
+            match name {
+                "discard" => Ok(ok),
+                _ if aborted => Ok(Resp::Error(
+                    "EXECABORT Transaction discarded because of previous errors.".to_owned(),
+                )),
+                _ => exec(engine, &watched, &queued, session.version),
+            }
+        }
+    }
+}
+
+/// run the queued commands as one transaction
+///
+/// Keys read by the commands are watched as well, the transaction is retried as long as
+/// only those changed, up to `TXN_ATTEMPTS` times. Once a key given to WATCH changed,
+/// nothing is applied.
+fn exec<T: KvsEngine>(
+    engine: &T,
+    watched: &[(Vec<u8>, u64)],
+    queued: &[Vec<Vec<u8>>],
+    proto: i64,
+) -> Result<Resp> {
+    for _ in 0..TXN_ATTEMPTS {
+        let mut staged = Staged {
+            engine,
+            txn: Transaction::new(),
+            written: HashMap::new(),
+        };
+        for (key, version) in watched {
+            staged.txn.watch(key.clone(), *version);
+        }
+        let replies = queued
+            .iter()
+            .map(|args| {
+                let name = String::from_utf8_lossy(&args[0]).to_lowercase();
+                match name.as_str() {
+                    "ping" | "echo" => echo(&name, &args[1..]),
+                    // EXEC forgets the watched keys anyway
+                    "unwatch" => Resp::Simple("OK".to_owned()),
+                    _ => execute_key(&mut staged, &name, &args[1..])
+                        .unwrap_or_else(|e| error(e.to_string())),
+                }
+            })
+            .collect();
+        match engine.commit(staged.txn) {
+            Ok(()) => return Ok(Resp::Array(replies)),
+            Err(e) if matches!(e.kind(), ErrorKind::Conflict) => {}
+            Err(e) => return Err(e),
+        }
+        for (key, version) in watched {
+            if engine.get_versioned(key.clone())?.1 != *version {
+                return Ok(if proto < 3 {
+                    Resp::NullArray
+                } else {
+                    Resp::Null
+                });
+            }
+        }
+    }
+    Err(Error::new(
+        ErrorKind::Conflict,
+        "keys read by the transaction kept changing",
+    ))
+}
+
+/// `COMMAND`, `COMMAND COUNT`, `COMMAND INFO name...` and an empty `COMMAND DOCS`
+fn command_info(args: &[Vec<u8>]) -> Result<Resp> {
+    let sub = args
+        .first()
+        .map(|arg| String::from_utf8_lossy(arg).to_lowercase());
+    match sub.as_deref() {
+        None => Ok(Resp::Array(COMMANDS.iter().map(Command::info).collect())),
+        Some("count") => Ok(Resp::Integer(COMMANDS.len() as i64)),
+        Some("docs") => Ok(Resp::Array(Vec::new())),
+        Some("info") => Ok(Resp::Array(
+            args[1..]
+                .iter()
+                .map(|name| {
+                    let name = String::from_utf8_lossy(name).to_lowercase();
+                    match COMMANDS.iter().find(|command| command.name == name) {
+                        Some(command) => command.info(),
+                        None => Resp::Null,
+                    }
+                })
+                .collect(),
+        )),
+        Some(sub) => Ok(error(format!("unknown subcommand '{}'", sub))),
+    }
+}
+
+/// `HELLO [protover [SETNAME name]]`, switches the protocol version and describes the server
+fn hello(session: &mut Session, args: &[Vec<u8>]) -> Resp {
+    let mut args = args
+        .iter()
+        .map(|arg| String::from_utf8_lossy(arg).into_owned());
+    let version = match args.next() {
+        None => session.version,
+        Some(version) => match version.parse() {
+            Ok(version @ 2..=3) => version,
+            _ => return Resp::Error("NOPROTO unsupported protocol version".to_owned()),
+        },
+    };
+    while let Some(option) = args.next() {
+        match (option.to_lowercase().as_str(), args.next()) {
+            ("setname", Some(name)) => info!("client calls itself {}", name),
+            ("auth", _) => return error("AUTH is not supported".to_owned()),
+            _ => return error(format!("syntax error in HELLO option '{}'", option)),
+        }
+    }
+    session.version = version;
+
+    let bulk = |s: &str| Resp::Bulk(s.as_bytes().to_vec());
+    Resp::Map(vec![
+        (bulk("server"), bulk("kvs")),
+        (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+        (bulk("proto"), Resp::Integer(version)),
+        (bulk("mode"), bulk("standalone")),
+        (bulk("role"), bulk("master")),
+        (bulk("modules"), Resp::Array(Vec::new())),
+    ])
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            version: DEFAULT_VERSION,
+            watched: Vec::new(),
+            queued: None,
+            aborted: false,
+        }
+    }
+}
+
+impl Session {
+    /// a command which could not be queued makes EXEC fail
+    fn reject(&mut self, reply: Resp) -> Resp {
+        if self.queued.is_some() {
+            self.aborted = true;
+        }
+        reply
+    }
+}
+
+impl<T: KvsEngine> Keys for Direct<'_, T> {
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.0.get_bytes(key)
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Option<Duration>) -> Result<()> {
+        match ttl {
+            Some(ttl) => self.0.set_with_ttl(key, value, ttl),
+            None => self.0.set_bytes(key, value),
+        }
+    }
+
+    /// with a time to live the condition is checked by a transaction,
+    /// retried on conflict up to `TXN_ATTEMPTS` times
+    fn set_if(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        exists: bool,
+        ttl: Option<Duration>,
+    ) -> Result<bool> {
+        let ttl = match (ttl, exists) {
+            (Some(ttl), _) => ttl,
+            (None, true) => return self.0.set_if_present(key, value),
+            (None, false) => return self.0.set_if_absent(key, value),
+        };
+        for _ in 0..TXN_ATTEMPTS {
+            let mut txn = Transaction::new();
+            if self.0.get_watched(&mut txn, key.clone())?.is_some() != exists {
+                return Ok(false);
+            }
+            txn.set_with_ttl(key.clone(), value.clone(), ttl);
+            match self.0.commit(txn) {
+                Ok(()) => return Ok(true),
+                Err(e) if matches!(e.kind(), ErrorKind::Conflict) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(Error::new(ErrorKind::Conflict, "the key kept changing"))
+    }
+
+    fn remove(&mut self, key: Vec<u8>) -> Result<bool> {
+        match self.0.remove_bytes(key) {
+            Ok(()) => Ok(true),
+            Err(e) if matches!(e.kind(), ErrorKind::KeyNotExist) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<T: KvsEngine> Keys for Staged<'_, T> {
+    /// see the writes of earlier commands, keys read from the engine are watched
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.written.get(&key) {
+            Some(value) => Ok(value.clone()),
+            None => self.engine.get_watched(&mut self.txn, key),
+        }
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Option<Duration>) -> Result<()> {
+        match ttl {
+            Some(ttl) => self.txn.set_with_ttl(key.clone(), value.clone(), ttl),
+            None => self.txn.set(key.clone(), value.clone()),
+        };
+        self.written.insert(key, Some(value));
+        Ok(())
+    }
+
+    fn set_if(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        exists: bool,
+        ttl: Option<Duration>,
+    ) -> Result<bool> {
+        if self.get(key.clone())?.is_some() != exists {
+            return Ok(false);
+        }
+        self.set(key, value, ttl)?;
+        Ok(true)
+    }
+
+    fn remove(&mut self, key: Vec<u8>) -> Result<bool> {
+        if self.get(key.clone())?.is_none() {
+            return Ok(false);
+        }
+        self.txn.remove(key.clone());
+        self.written.insert(key, None);
+        Ok(true)
+    }
+}
+
+impl Command {
+    /// the reply format of redis `COMMAND INFO`
+    fn info(&self) -> Resp {
+        let flags = self
+            .flags
+            .iter()
+            .map(|flag| Resp::Simple((*flag).to_owned()))
+            .collect();
+        Resp::Array(vec![
+            Resp::Bulk(self.name.as_bytes().to_vec()),
+            Resp::Integer(self.arity),
+            Resp::Array(flags),
+            Resp::Integer(self.keys.0),
+            Resp::Integer(self.keys.1),
+            Resp::Integer(self.keys.2),
+        ])
+    }
+}
+
+/// an integer argument
+fn integer(arg: &[u8]) -> Option<i64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// error reply, which has to fit on one line
+fn error(msg: String) -> Resp {
+    Resp::Error(format!("ERR {}", msg.replace(&['\r', '\n'][..], " ")))
+}
@@ -1,25 +1,57 @@
-use std::net::{SocketAddr, TcpListener};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use std::{io, thread};
 
-use log::info;
+use log::{error, info};
 use signal_hook::SIGINT;
 
-use crate::{utils, Error, KvsEngine, Result};
+use crate::config::IDLE_TIMEOUT;
+use crate::{Error, KvsEngine, Result, ThreadPool};
+
+use super::{redis, wire};
 
 /// server
-pub struct KvsServer<T: KvsEngine> {
+pub struct KvsServer<T: KvsEngine, P: ThreadPool> {
     engine: T,
+    pool: P,
     listener: TcpListener,
+    idle_timeout: Duration,
+    protocol: Protocol,
+}
+
+/// wire protocol spoken by the server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    /// framed bincode requests as sent by `KvsClient`, see `kv::wire`
+    Kvs,
+    /// redis serialization protocol, for `redis-cli` and redis client libraries
+    Resp,
 }
 
-impl<T: KvsEngine> KvsServer<T> {
-    /// listen to the socket address
-    pub fn listen(engine: T, addr: SocketAddr) -> Result<Self> {
+impl<T: KvsEngine, P: ThreadPool> KvsServer<T, P> {
+    /// listen to the socket address, connections are handled on the given pool
+    pub fn listen(engine: T, pool: P, addr: SocketAddr) -> Result<Self> {
         let listener = TcpListener::bind(addr)?;
-        Ok(Self { engine, listener })
+        Ok(Self {
+            engine,
+            pool,
+            listener,
+            idle_timeout: IDLE_TIMEOUT,
+            protocol: Protocol::Kvs,
+        })
+    }
+
+    /// speak the given protocol with all clients, `Protocol::Kvs` by default
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// close connections which have not sent a request for the given time, must not be zero
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = timeout;
     }
 
     /// serve
@@ -39,46 +71,62 @@ impl<T: KvsEngine> KvsServer<T> {
                 }
                 Err(e) => return Err(Error::from(e)),
                 Ok(stream) => {
-                    info!("peer connected {}", stream.peer_addr()?);
-                    let req: utils::Request = bincode::deserialize_from(&stream)?;
-                    match req {
-                        utils::Request::Get(key) => {
-                            info!("incoming request GET {}", key);
-                            bincode::serialize_into(
-                                &stream,
-                                &self
-                                    .engine
-                                    .get(key.clone())
-                                    .map(utils::Respond::Ok)
-                                    .unwrap_or_else(|e| utils::Respond::Err(e.to_string())),
-                            )?;
-                        }
-                        utils::Request::Set(key, value) => {
-                            info!("incoming request SET {} {}", key, value);
-                            bincode::serialize_into(
-                                &stream,
-                                &self
-                                    .engine
-                                    .set(key, value)
-                                    .map(|_| utils::Respond::Ok(None))
-                                    .unwrap_or_else(|e| utils::Respond::Err(e.to_string())),
-                            )?;
-                        }
-                        utils::Request::Rm(key) => {
-                            info!("incoming request RM {}", key);
-                            bincode::serialize_into(
-                                &stream,
-                                &self
-                                    .engine
-                                    .remove(key)
-                                    .map(|_| utils::Respond::Ok(None))
-                                    .unwrap_or_else(|e| utils::Respond::Err(e.to_string())),
-                            )?;
+                    let engine = self.engine.clone();
+                    let idle_timeout = self.idle_timeout;
+                    let protocol = self.protocol;
+                    self.pool.spawn(move || {
+                        if let Err(e) = handle(engine, stream, idle_timeout, protocol) {
+                            error!("failed to serve connection: {}", e);
                         }
-                    };
+                    });
                 }
             }
         }
         Ok(())
     }
 }
+
+/// answer requests sent over the connection until the peer closes it or stays idle
+fn handle<T: KvsEngine>(
+    engine: T,
+    stream: TcpStream,
+    idle_timeout: Duration,
+    protocol: Protocol,
+) -> Result<()> {
+    // accepted streams must not inherit the non-blocking mode of the listener
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(idle_timeout))?;
+    let peer = stream.peer_addr()?;
+    info!("peer connected {}", peer);
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let mut kvs_session = wire::Session::default();
+    let mut resp_session = redis::Session::default();
+    loop {
+        match reader.fill_buf() {
+            Ok([]) => break,
+            Ok(_) => {}
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                info!("closing idle connection {}", peer);
+                break;
+            }
+            Err(e) => return Err(Error::from(e)),
+        }
+        let open = match protocol {
+            Protocol::Kvs => wire::serve(&engine, &mut kvs_session, &mut reader, &mut writer)?,
+            Protocol::Resp => redis::serve(&engine, &mut resp_session, &mut reader, &mut writer)?,
+        };
+        // requests which have already arrived are answered first,
+        // so a pipeline of requests gets its replies in a single write
+        if !open || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if !open {
+            break;
+        }
+    }
+    info!("peer disconnected {}", peer);
+    Ok(())
+}
@@ -1,32 +1,440 @@
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ops::RangeBounds;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::{KvsEngine, Result};
+use sled::{
+    ConflictableTransactionError, ConflictableTransactionResult, IVec, TransactionError,
+    Transactional, TransactionalTree,
+};
 
+use super::batch::Op;
+use super::clock::Clock;
+use super::ticker::Ticker;
+use crate::config::{EXPIRE_BATCH, EXPIRE_INTERVAL};
+use crate::{utils, Error, ErrorKind, KvsEngine, Result, Scan, Transaction, WriteBatch};
+
+/// tree mapping keys with a time to live to their expiry, big endian unix milliseconds
+const EXPIRY_TREE: &str = "__kvs_expiry";
+/// the same keys ordered by expiry, the big endian expiry followed by the key
+const DEADLINE_TREE: &str = "__kvs_deadlines";
+/// tree mapping keys to their version, big endian
+const VERSION_TREE: &str = "__kvs_versions";
+
+/// `KvsEngine` backed by the sled embedded database
+///
+/// Writes are flushed before returning so they survive the process being killed.
+/// Expiries and versions are kept in trees of their own,
+/// written in the same transaction as the value.
+#[derive(Debug, Clone)]
 pub struct SledKvsEngine {
     db: sled::Db,
+    expiry: sled::Tree,
+    deadlines: sled::Tree,
+    versions: sled::Tree,
+    /// the time expiries are judged by
+    clock: Clock,
+    /// removes expired keys in the background, stopped with the last handle
+    _sweeper: Arc<Ticker>,
 }
 
 impl KvsEngine for SledKvsEngine {
     fn open(dir: impl Into<PathBuf>) -> Result<Self> {
-        let db = sled::open(dir.into())?;
-        Ok(Self { db })
+        Self::open_at(dir.into(), Clock::system())
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.transaction(|trees| trees.set(&key, &value, None))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if self.expired(&key)? {
+            return Ok(None);
+        }
+        Ok(self.db.get(key)?.map(|iv| iv.to_vec()))
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires = self.clock.deadline(ttl);
+        self.transaction(|trees| trees.set(&key, &value, Some(expires)))
+    }
+
+    fn expire(&self, key: Vec<u8>, ttl: Duration) -> Result<bool> {
+        let expires = self.clock.deadline(ttl);
+        self.transaction(|trees| {
+            if trees.get(&key)?.is_none() {
+                return Ok(false);
+            }
+            trees.set_expiry(&key, Some(expires))?;
+            trees.touch(&key)?;
+            Ok(true)
+        })
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let expires = self.expiry.get(&key)?.map(|iv| decode(&iv));
+        let now = self.clock.now_millis();
+        if utils::is_expired_at(expires, now) || !self.db.contains_key(&key)? {
+            return Err(Error::from(ErrorKind::KeyNotExist));
+        }
+        Ok(expires.map(|expires| Duration::from_millis(expires - now)))
+    }
+
+    fn persist(&self, key: Vec<u8>) -> Result<bool> {
+        self.transaction(|trees| {
+            if trees.get(&key)?.is_none() || !trees.set_expiry(&key, None)? {
+                return Ok(false);
+            }
+            trees.touch(&key)?;
+            Ok(true)
+        })
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.transaction(|trees| {
+            let current = trees.get(&key)?;
+            if current.as_deref() != expected.as_deref() {
+                return Ok(false);
+            }
+            match &new {
+                Some(value) => trees.set(&key, value, None)?,
+                None => trees.remove(&key)?,
+            }
+            Ok(true)
+        })
+    }
+
+    fn set_if_present(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.transaction(|trees| {
+            if trees.get(&key)?.is_none() {
+                return Ok(false);
+            }
+            trees.set(&key, &value, None)?;
+            Ok(true)
+        })
+    }
+
+    /// Get the value of a key along with its version.
+    ///
+    /// Versions are ids handed out by sled, which never repeat, kept in a tree of their own.
+    fn get_versioned(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        self.run(|trees| {
+            let value = trees.get(&key)?.map(|iv| iv.to_vec());
+            Ok((value, trees.version(&key)?))
+        })
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.transaction(|trees| trees.apply(&batch))
+    }
+
+    fn commit(&self, txn: Transaction) -> Result<()> {
+        let (watched, batch) = (txn.watched().to_vec(), txn.into_writes());
+        self.transaction(|trees| {
+            for (key, expected) in &watched {
+                if trees.version(key)? != *expected {
+                    let msg = format!("{} changed", String::from_utf8_lossy(key));
+                    let e = Error::new(ErrorKind::Conflict, msg);
+                    return Err(ConflictableTransactionError::Abort(e));
+                }
+            }
+            trees.apply(&batch)
+        })
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Scan> {
+        Ok(unexpired(
+            self.expiry.clone(),
+            self.clock.clone(),
+            self.db.range(range),
+        ))
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Result<Scan> {
+        Ok(unexpired(
+            self.expiry.clone(),
+            self.clock.clone(),
+            self.db.scan_prefix(prefix),
+        ))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.transaction(|trees| {
+            if trees.get(&key)?.is_none() {
+                let e = Error::from(ErrorKind::KeyNotExist);
+                return Err(ConflictableTransactionError::Abort(e));
+            }
+            trees.remove(&key)
+        })
+    }
+}
+
+impl SledKvsEngine {
+    /// Open the database in the given directory, judging expiries by the given clock.
+    #[cfg(feature = "testing")]
+    pub fn open_with_clock(dir: impl Into<PathBuf>, clock: Clock) -> Result<Self> {
+        Self::open_at(dir.into(), clock)
+    }
+
+    fn open_at(dir: PathBuf, clock: Clock) -> Result<Self> {
+        let db = sled::open(dir)?;
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+        let deadlines = db.open_tree(DEADLINE_TREE)?;
+        let versions = db.open_tree(VERSION_TREE)?;
+        let sweeper = {
+            let trees = (expiry.clone(), deadlines.clone(), versions.clone());
+            let (db, clock) = (db.clone(), clock.clone());
+            Ticker::new("sweeper", EXPIRE_INTERVAL, move || {
+                let (expiry, deadlines, versions) = &trees;
+                sweep(&db, expiry, deadlines, versions, &clock)
+            })?
+        };
+        Ok(Self {
+            db,
+            expiry,
+            deadlines,
+            versions,
+            clock,
+            _sweeper: Arc::new(sweeper),
+        })
+    }
+
+    /// run `f` on the trees in one transaction and flush once it went through
+    fn transaction<A>(&self, f: impl Fn(&Trees) -> TxResult<A, Error>) -> Result<A> {
+        let value = self.run(f)?;
+        self.db.flush()?;
+        Ok(value)
+    }
+
+    /// run `f` on the trees in one transaction
+    fn run<A>(&self, f: impl Fn(&Trees) -> TxResult<A, Error>) -> Result<A> {
+        // transactions over several trees cannot abort with an error of their own
+        let aborted = RefCell::new(None);
+        let all = (&*self.db, &self.expiry, &self.deadlines, &self.versions);
+        let res = all.transaction(|(tx, ex, dl, vs)| {
+            let trees = Trees {
+                db: &self.db,
+                values: tx,
+                expiry: ex,
+                deadlines: dl,
+                versions: vs,
+                clock: &self.clock,
+            };
+            match f(&trees) {
+                Ok(value) => Ok(value),
+                Err(ConflictableTransactionError::Abort(e)) => {
+                    *aborted.borrow_mut() = Some(e);
+                    Err(ConflictableTransactionError::Abort(()))
+                }
+                Err(ConflictableTransactionError::Storage(e)) => {
+                    Err(ConflictableTransactionError::Storage(e))
+                }
+                Err(_) => Err(ConflictableTransactionError::Conflict),
+            }
+        });
+        match res {
+            Ok(value) => Ok(value),
+            Err(TransactionError::Abort(())) => Err(aborted.into_inner().unwrap()),
+            Err(TransactionError::Storage(e)) => Err(Error::from(e)),
+        }
+    }
+
+    /// whether the key has an expiry which passed
+    fn expired(&self, key: &[u8]) -> Result<bool> {
+        let expires = self.expiry.get(key)?.map(|iv| decode(&iv));
+        Ok(self.clock.is_expired(expires))
     }
+}
+
+type TxResult<A, E> = ConflictableTransactionResult<A, E>;
+
+/// the trees of the engine within one transaction
+struct Trees<'a> {
+    db: &'a sled::Db,
+    values: &'a TransactionalTree,
+    expiry: &'a TransactionalTree,
+    deadlines: &'a TransactionalTree,
+    versions: &'a TransactionalTree,
+    clock: &'a Clock,
+}
 
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.db.insert(key.as_bytes(), value.as_bytes())?;
+impl Trees<'_> {
+    /// the value of a key, `None` if it expired
+    fn get<E>(&self, key: &[u8]) -> TxResult<Option<IVec>, E> {
+        let expires = self.expiry.get(key)?.map(|iv| decode(&iv));
+        if self.clock.is_expired(expires) {
+            return Ok(None);
+        }
+        Ok(self.values.get(key)?)
+    }
+
+    /// the version of a key, 0 if it does not exist
+    fn version<E>(&self, key: &[u8]) -> TxResult<u64, E> {
+        if self.get(key)?.is_none() {
+            return Ok(0);
+        }
+        // keys not written since versions were kept count as version 1
+        Ok(self.versions.get(key)?.map_or(1, |iv| decode(&iv)))
+    }
+
+    /// set the key, which expires at the given unix time in milliseconds if any
+    fn set<E>(&self, key: &[u8], value: &[u8], expires: Option<u64>) -> TxResult<(), E> {
+        self.values.insert(key, value)?;
+        self.set_expiry(key, expires)?;
+        self.touch(key)
+    }
+
+    fn remove<E>(&self, key: &[u8]) -> TxResult<(), E> {
+        self.values.remove(key)?;
+        self.set_expiry(key, None)?;
+        self.versions.remove(key)?;
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        let val = self.db.get(key)?;
-        match val {
-            None => Ok(None),
-            Some(iv) => Ok(Some(String::from_utf8(iv.to_vec())?)),
+    /// replace the expiry of the key in both expiry trees, tell whether it had one
+    fn set_expiry<E>(&self, key: &[u8], expires: Option<u64>) -> TxResult<bool, E> {
+        let old = match expires {
+            Some(expires) => self.expiry.insert(key, &expires.to_be_bytes())?,
+            None => self.expiry.remove(key)?,
+        };
+        if let Some(old) = &old {
+            self.deadlines.remove(deadline_key(decode(old), key))?;
+        }
+        if let Some(expires) = expires {
+            self.deadlines.insert(deadline_key(expires, key), &[])?;
         }
+        Ok(old.is_some())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        self.db.remove(key)?;
+    /// give the key a version it never had before
+    fn touch<E>(&self, key: &[u8]) -> TxResult<(), E> {
+        // ids start at 0, which stands for an absent key, and 1 is taken above
+        let version = self.db.generate_id()? + 2;
+        self.versions.insert(key, &version.to_be_bytes())?;
         Ok(())
     }
+
+    /// the operations of a batch
+    fn apply(&self, batch: &WriteBatch) -> TxResult<(), Error> {
+        for op in batch.ops() {
+            match op {
+                Op::Set(key, value) => self.set(key, value, None)?,
+                Op::SetWithTtl(key, value, ttl) => {
+                    let expires = self.clock.deadline(Duration::from_millis(*ttl));
+                    self.set(key, value, Some(expires))?
+                }
+                Op::Rm(key) => {
+                    if self.get(key)?.is_none() {
+                        let e = Error::from(ErrorKind::KeyNotExist);
+                        return Err(ConflictableTransactionError::Abort(e));
+                    }
+                    self.remove(key)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// remove the keys which expired by now, a batch at a time
+///
+/// the deadline tree is ordered by expiry, so the sweep stops at the first key still alive
+fn sweep(
+    db: &sled::Db,
+    expiry: &sled::Tree,
+    deadlines: &sled::Tree,
+    versions: &sled::Tree,
+    clock: &Clock,
+) -> Result<()> {
+    let end = deadline_key(clock.now_millis(), &[]);
+    let mut expired = Vec::new();
+    for res in deadlines.range(..end) {
+        let (entry, _) = res?;
+        expired.push(IVec::from(&entry[8..]));
+        if expired.len() == EXPIRE_BATCH {
+            remove_expired(db, expiry, deadlines, versions, clock, &expired)?;
+            expired.clear();
+        }
+    }
+    remove_expired(db, expiry, deadlines, versions, clock, &expired)
+}
+
+/// remove the keys unless they were written again since they expired
+fn remove_expired(
+    db: &sled::Db,
+    expiry: &sled::Tree,
+    deadlines: &sled::Tree,
+    versions: &sled::Tree,
+    clock: &Clock,
+    keys: &[IVec],
+) -> Result<()> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let res: std::result::Result<(), TransactionError<()>> = (&**db, expiry, deadlines, versions)
+        .transaction(|(tx, ex, dl, vs)| {
+            let trees = Trees {
+                db,
+                values: tx,
+                expiry: ex,
+                deadlines: dl,
+                versions: vs,
+                clock,
+            };
+            for key in keys {
+                let expires = ex.get(key)?.map(|iv| decode(&iv));
+                if clock.is_expired(expires) {
+                    trees.remove(key)?;
+                }
+            }
+            Ok(())
+        });
+    match res {
+        Ok(()) => {}
+        Err(TransactionError::Abort(())) => unreachable!("removing expired keys never aborts"),
+        Err(TransactionError::Storage(e)) => return Err(Error::from(e)),
+    }
+    db.flush()?;
+    Ok(())
+}
+
+/// drop the pairs whose key expired from a scan
+fn unexpired(
+    expiry: sled::Tree,
+    clock: Clock,
+    iter: impl Iterator<Item = sled::Result<(IVec, IVec)>> + 'static,
+) -> Scan {
+    let iter = iter.filter_map(move |res| {
+        let live = res.and_then(|(key, value)| {
+            let expires = expiry.get(&key)?.map(|iv| decode(&iv));
+            Ok((!clock.is_expired(expires), key, value))
+        });
+        match live {
+            Ok((true, key, value)) => Some(Ok((key.to_vec(), value.to_vec()))),
+            Ok((false, ..)) => None,
+            Err(e) => Some(Err(Error::from(e))),
+        }
+    });
+    Box::new(iter)
+}
+
+/// the key of the deadline tree for a key expiring at the given time
+fn deadline_key(expires: u64, key: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 + key.len());
+    buf.extend_from_slice(&expires.to_be_bytes());
+    buf.extend_from_slice(key);
+    buf
+}
+
+/// a big endian u64 as stored in the expiry and version trees
+fn decode(buf: &[u8]) -> u64 {
+    // a malformed expiry never expires rather than losing the key
+    <[u8; 8]>::try_from(buf).map_or(u64::MAX, u64::from_be_bytes)
 }
@@ -1,135 +1,988 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::ffi::OsStr;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fs;
-use std::io::{Seek, SeekFrom};
-use std::path::PathBuf;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
+use ::log::error;
+
+use super::batch::{Op, WriteBatch};
+use super::clock::Clock;
+use super::compactor::Compactor;
+use super::ticker::Ticker;
+use super::transaction::Transaction;
 use crate::config::*;
 use crate::error::{Error, ErrorKind, Result};
-use crate::log::{self, Segment};
-use crate::KvsEngine;
+use crate::log::{self, Manifest, Segment};
+use crate::{utils, KvsEngine, Scan};
 
-/// A simple key-value store implementation which wraps around std `HashMap`
+/// A key-value store persisted in a log of segment files
+///
+/// Writes are appended to the active segment, which is sealed once it grows past
+/// `segment_size`, the manifest records the live segments in replay order.
+/// An in-memory index points at the latest record of every key and is rebuilt
+/// from the hint files written next to sealed segments when the store is opened.
+/// A background compactor rewrites sealed segments holding mostly garbage,
+/// and expired keys are removed by a background sweep.
+///
+/// Writes survive the process crashing, `Durability` decides how hard
+/// they are forced to disk to also survive a power failure.
 ///
-/// Key-value pairs are stored in a `HashMap` which means it's not durable and persistent
+/// Cloning the store is cheap, every clone operates on the same database
+/// while keeping its own set of open log files for reading.
 ///
 /// Example:
 ///
 /// ```rust
+/// # fn main() -> kvs::Result<()> {
+/// use kvs::{KvStore, KvsEngine};
+///
+/// let dir = tempfile::TempDir::new()?;
+/// let store = KvStore::open(dir.path())?;
+/// store.set("key1".to_owned(), "value1".to_owned())?;
+/// store.set("key2".to_owned(), "value2".to_owned())?;
+/// assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+///
+/// let pairs = store
+///     .scan(b"key1".to_vec()..)?
+///     .collect::<kvs::Result<Vec<_>>>()?;
+/// assert_eq!(
+///     pairs,
+///     vec![
+///         (b"key1".to_vec(), b"value1".to_vec()),
+///         (b"key2".to_vec(), b"value2".to_vec()),
+///     ]
+/// );
+/// # Ok(())
+/// # }
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct KvStore {
+    shared: Arc<Shared>,
+    /// never read, keeps the sweeper and syncer running until the last handle drops them
+    _tasks: Arc<Tasks>,
+    /// log files opened by this handle
+    readers: Readers,
+}
+
+/// state shared by all clones of a store
+#[derive(Debug)]
+struct Shared {
     /// the directory that contains database files
     full_path: PathBuf,
-    /// active database segment
-    active: RefCell<Segment>,
-    /// index
-    memtbl: MemTable,
-    /// use set_count to decide whether to perform compaction
-    set_count: u64,
+    /// active database segment, the lock serializes writers
+    active: Mutex<Segment>,
+    /// index, shared with the compactor
+    memtbl: Arc<RwLock<MemTable>>,
+    /// live segments in replay order, shared with the compactor
+    manifest: Arc<Mutex<Manifest>>,
+    /// rewrites sealed segments in the background
+    compactor: Compactor,
+    options: KvStoreOptions,
+    /// the time expiries are judged by
+    time: Clock,
 }
 
-const COMPACTION_THRESHOLD: u64 = 8 * 1024;
-const SEGMENT_SIZE_THRESHOLD: u64 = 4 * 1024;
+/// threads working on the shared state in the background
+#[derive(Debug)]
+struct Tasks {
+    /// writes tombstones for expired keys
+    _sweeper: Ticker,
+    /// syncs the active segment if the durability asks for it
+    _syncer: Option<Ticker>,
+}
+
+/// cache of read only handles to log files
+/// a clone starts with an empty cache so handles are never shared between threads
+#[derive(Debug, Default)]
+struct Readers {
+    files: RefCell<HashMap<PathBuf, fs::File>>,
+}
+
+/// a read-only view of a `KvStore` as it was when `KvStore::snapshot` was called
+///
+/// Values overwritten, removed or expired later stay readable until the snapshot is dropped,
+/// along with the segments holding them, which compaction leaves in place until then.
+#[derive(Debug)]
+pub struct Snapshot {
+    store: KvStore,
+    pin: Arc<Pin>,
+}
 
-/// in memory representation of the index
+/// a snapshot registered with the index, released once it and its scans are gone
 #[derive(Debug)]
-struct MemTable {
-    map: HashMap<String, log::Pointer>,
+struct Pin {
+    shared: Arc<Shared>,
+    seq: u64,
+    /// unix time in milliseconds the snapshot was taken at, expiry is judged by it
+    taken: u64,
+}
+
+/// iterator returned by `KvStore::scan` and `Snapshot::scan`
+///
+/// Pairs are read in batches, the index is only locked while a batch is read.
+struct StoreScan {
+    store: KvStore,
+    /// the snapshot read from, the latest values if `None`
+    pin: Option<Arc<Pin>>,
+    /// where the next batch starts
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    batch: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+/// Tunables of a `KvStore`
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    /// the active segment is sealed and a new one is started once it grows past this size
+    pub segment_size: u64,
+    /// sealed segments with a larger fraction of dead bytes are compacted
+    pub garbage_ratio: f64,
+    /// when writes are forced to disk
+    pub durability: Durability,
+}
+
+/// how hard a `KvStore` tries to keep acknowledged writes across a power failure
+///
+/// Writes always survive the process crashing, as they are handed to the OS right away.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// every write is synced to disk before it returns
+    Always,
+    /// a background thread syncs at the given interval, at most that much is lost
+    Periodic(Duration),
+    /// the OS decides when writes reach the disk
+    Os,
+}
+
+const SEGMENT_SIZE_THRESHOLD: u64 = 1024 * 1024;
+const GARBAGE_RATIO_THRESHOLD: f64 = 0.5;
+
+/// in memory representation of the index, ordered by key for scans
+#[derive(Debug)]
+pub(crate) struct MemTable {
+    pub(crate) map: BTreeMap<Vec<u8>, Slot>,
+    /// space usage of every segment, including the active one
+    pub(crate) usage: HashMap<PathBuf, Usage>,
+    /// sequence number of the last write, also the version given to the key written
+    /// starts past the versions handed out before the store was last opened
+    clock: u64,
+    /// values replaced since a snapshot was taken, as long as the snapshot is alive
+    history: BTreeMap<Vec<u8>, Vec<Shadowed>>,
+    /// sequence numbers of the live snapshots and how many were taken at each
+    snapshots: BTreeMap<u64, usize>,
+    /// how many replaced values each segment holds
+    pins: HashMap<PathBuf, usize>,
+    /// compacted segments which are deleted once nothing is pinned in them
+    retired: HashSet<PathBuf>,
+    /// keys with an expiry ordered by when they expire
+    expiring: BTreeSet<(u64, Vec<u8>)>,
+    /// the time expiries are judged by
+    time: Clock,
+}
+
+/// where the value of a key lives and which version of the key it is
+#[derive(Debug)]
+pub(crate) struct Slot {
+    pub(crate) pointer: log::Pointer,
+    /// changes whenever the key is written, never 0 which stands for an absent key
+    pub(crate) version: u64,
+    /// unix time in milliseconds from which the key no longer exists
+    pub(crate) expires: Option<u64>,
+}
+
+/// a value replaced by a later write, kept for the snapshots taken in between
+#[derive(Debug)]
+pub(crate) struct Shadowed {
+    /// sequence number of the write which replaced the value
+    replaced: u64,
+    slot: Slot,
+}
+
+/// live and dead bytes of a segment
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Usage {
+    pub(crate) total: u64,
+    pub(crate) dead: u64,
 }
 
 impl KvStore {
-    fn list_segments(dir: &PathBuf) -> Result<Vec<PathBuf>> {
-        let mut segments = Vec::new();
-        // scan through all the log files
-        for res in fs::read_dir(&dir)? {
-            let entry = res?;
-            let path = entry.path();
-            if path.is_file() && path.extension() == Some(&OsStr::new(LOG_FILE_EXT)) {
-                segments.push(path);
+    /// Open the KvStore at a given path with custom options.
+    pub fn open_with(dir: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
+        Self::open_at(dir.into(), options, Clock::system())
+    }
+
+    /// Open the KvStore at a given path with custom options,
+    /// judging expiries by the given clock.
+    #[cfg(feature = "testing")]
+    pub fn open_with_clock(
+        dir: impl Into<PathBuf>,
+        options: KvStoreOptions,
+        clock: Clock,
+    ) -> Result<Self> {
+        Self::open_at(dir.into(), options, clock)
+    }
+
+    fn open_at(dir: PathBuf, options: KvStoreOptions, clock: Clock) -> Result<Self> {
+        let mut manifest = log::upgrade(&dir)?;
+        manifest.remove_orphans()?;
+        let mut memtbl = MemTable::new(manifest.next_epoch()?, clock);
+        let segments = manifest.segments();
+        if segments.is_empty() {
+            return Self::new(dir, manifest, memtbl, options);
+        }
+
+        // only the newest segment could have been written to when we crashed,
+        // the older ones must be intact
+        let mut tail = None;
+        let last = segments.len() - 1;
+        for (i, seg) in segments.into_iter().enumerate() {
+            if !seg.exists() {
+                return Err(Error::new(
+                    ErrorKind::InvalidManifest,
+                    format!("missing segment {:?}", seg),
+                ));
+            }
+            if i == last && !Segment::is_sealed(&seg)? {
+                let seg = Segment::open(seg)?;
+                memtbl.load(seg.path(), seg.hint());
+                tail = Some(seg);
+            } else {
+                let hint = log::Hint::open(&seg, false)?;
+                memtbl.load(&seg, &hint);
             }
         }
-        // log files are created by time order, which should be in ascending order
-        segments.sort();
-        Ok(segments)
+        // keep appending to the newest segment unless it is already full
+        let active = match tail {
+            Some(seg) if seg.size() < options.segment_size => seg,
+            Some(seg) => {
+                seg.seal()?;
+                let active = Segment::new(dir.clone())?;
+                manifest.push(active.path())?;
+                active
+            }
+            None => {
+                let active = Segment::new(dir.clone())?;
+                manifest.push(active.path())?;
+                active
+            }
+        };
+        Self::with_parts(dir, active, memtbl, manifest, options)
     }
 
-    fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+    fn new(
+        dir: impl Into<PathBuf>,
+        mut manifest: Manifest,
+        memtbl: MemTable,
+        options: KvStoreOptions,
+    ) -> Result<Self> {
         let full_path = dir.into();
         let active = Segment::new(full_path.clone())?;
-        Ok(Self {
+        manifest.push(active.path())?;
+        Self::with_parts(full_path, active, memtbl, manifest, options)
+    }
+
+    fn with_parts(
+        full_path: PathBuf,
+        active: Segment,
+        memtbl: MemTable,
+        manifest: Manifest,
+        options: KvStoreOptions,
+    ) -> Result<Self> {
+        let time = memtbl.time.clone();
+        let memtbl = Arc::new(RwLock::new(memtbl));
+        let manifest = Arc::new(Mutex::new(manifest));
+        let compactor = Compactor::new(
+            Arc::clone(&memtbl),
+            Arc::clone(&manifest),
+            options.clone(),
+            time.clone(),
+        )?;
+        let shared = Arc::new(Shared {
             full_path,
-            active: RefCell::new(active),
-            memtbl: MemTable::default(),
-            set_count: 0,
+            active: Mutex::new(active),
+            memtbl,
+            manifest,
+            compactor,
+            options,
+            time,
+        });
+        let sweeper = {
+            let shared = Arc::clone(&shared);
+            Ticker::new("sweeper", EXPIRE_INTERVAL, move || shared.sweep())?
+        };
+        let syncer = match shared.options.durability {
+            Durability::Periodic(interval) => {
+                let shared = Arc::clone(&shared);
+                // only the log, the hint is written back when the segment is sealed
+                let sync = move || shared.active.lock().unwrap().sync();
+                Some(Ticker::new("syncer", interval, sync)?)
+            }
+            Durability::Always | Durability::Os => None,
+        };
+        let tasks = Tasks {
+            _sweeper: sweeper,
+            _syncer: syncer,
+        };
+        Ok(Self {
+            shared,
+            _tasks: Arc::new(tasks),
+            readers: Readers::default(),
         })
     }
+}
+
+impl KvStore {
+    /// Take a consistent read-only view of the store as it is now.
+    ///
+    /// Writes made afterwards are not seen through the snapshot.
+    pub fn snapshot(&self) -> Snapshot {
+        let seq = self.shared.memtbl.write().unwrap().pin();
+        let pin = Pin {
+            shared: Arc::clone(&self.shared),
+            seq,
+            taken: self.shared.time.now_millis(),
+        };
+        Snapshot {
+            store: self.clone(),
+            pin: Arc::new(pin),
+        }
+    }
+
+    /// Force the writes made so far to disk, whatever the durability.
+    ///
+    /// The index of the active segment is written back as well,
+    /// so reopening the store after a crash does not have to replay its log.
+    pub fn sync(&self) -> Result<()> {
+        self.shared.active.lock().unwrap().checkpoint()
+    }
+
+    /// Remove the keys which expired by now right away
+    /// rather than waiting for the background sweep.
+    #[cfg(feature = "testing")]
+    pub fn sweep(&self) -> Result<()> {
+        self.shared.sweep()
+    }
+
+    /// Wait for the running compaction, if any, and swap in what it wrote.
+    #[cfg(feature = "testing")]
+    pub fn wait_compaction(&self) {
+        // holding the writer lock, as the writer is the one installing compactions
+        let _active = self.shared.active.lock().unwrap();
+        self.shared.compactor.wait();
+        self.shared.compactor.install();
+    }
+
+    /// the value of a key and its version as seen by the snapshot, the latest if `None`
+    fn read_at(&self, key: &[u8], pin: Option<&Pin>) -> Result<(Option<Vec<u8>>, u64)> {
+        let memtbl = self.shared.memtbl.read().unwrap();
+        match memtbl.slot_at(key, pin) {
+            None => Ok((None, 0)),
+            Some(slot) => match self.readers.read(&slot.pointer, &memtbl)? {
+                log::Entry::Set(_, v) | log::Entry::SetEx(_, v, _) => Ok((Some(v), slot.version)),
+                _ => Err(Error::from(ErrorKind::InvalidLogEntry)),
+            },
+        }
+    }
+}
+
+impl Snapshot {
+    /// the sequence number of the last write the snapshot sees
+    pub fn seq(&self) -> u64 {
+        self.pin.seq
+    }
+
+    /// Get the value a key had when the snapshot was taken.
+    pub fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.store.read_at(&key, Some(&self.pin))?.0)
+    }
 
-    fn get_no_mut(&self, key: String) -> Result<Option<String>> {
-        match self.memtbl.map.get(&key) {
+    /// Get the string value a string key had when the snapshot was taken.
+    /// Return an error if the value is not valid UTF-8.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
             None => Ok(None),
-            Some(pointer) => {
-                let file = pointer.path();
-                if file == self.active.borrow().path() {
-                    Ok(self.active.borrow_mut().get(&key)?)
-                } else {
-                    let mut file = fs::File::with_options().read(true).open(file)?;
-                    file.seek(SeekFrom::Start(pointer.offset()))?;
-                    let entry: log::Entry = bincode::deserialize_from(file)?;
-                    if let log::Entry::Set(_, v) = entry {
-                        Ok(Some(v))
-                    } else {
-                        Err(Error::from(ErrorKind::InvalidLogEntry))
-                    }
-                }
+        }
+    }
+
+    /// Iterate over the pairs whose key lay in the range when the snapshot was taken,
+    /// in ascending key order. The iterator keeps the snapshot alive.
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Scan> {
+        let pin = Some(Arc::clone(&self.pin));
+        Ok(Box::new(StoreScan::new(self.store.clone(), pin, range)))
+    }
+
+    /// Iterate over the pairs whose key started with the prefix when the snapshot was taken.
+    pub fn scan_prefix(&self, prefix: Vec<u8>) -> Result<Scan> {
+        let end = match utils::prefix_end(&prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.scan((Bound::Included(prefix), end))
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // the outputs of a running compaction would be orphans otherwise
+        self.compactor.stop();
+        self.compactor.install();
+        // anything written since the last tick of the syncer
+        if self.options.durability != Durability::Os {
+            if let Err(e) = self.active.lock().unwrap().sync() {
+                error!("failed to sync the active segment: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        let free = self.shared.memtbl.write().unwrap().unpin(self.seq);
+        for path in free {
+            if let Err(e) = Segment::delete(&path) {
+                error!("failed to delete retired segment {:?}: {}", path, e);
             }
         }
     }
+}
+
+impl Shared {
+    /// make a write durable as the options ask before `index` makes it visible,
+    /// then rotate the active segment if it is full,
+    /// called after every write with the writer lock held
+    fn finish(&self, active: &mut Segment, index: impl FnOnce(&mut MemTable)) -> Result<()> {
+        if self.options.durability == Durability::Always {
+            active.sync()?;
+        }
+        index(&mut self.memtbl.write().unwrap());
+        self.compactor.install();
+        self.rotate(active)
+    }
 
-    fn set_no_compact(&mut self, key: String, value: String) -> Result<()> {
-        let pointer = self.active.borrow_mut().set(key.clone(), value)?;
-        self.memtbl.map.insert(key, pointer);
+    /// seal the active segment and start a new one once it is full
+    /// a freshly sealed segment is a good time to look for garbage
+    fn rotate(&self, active: &mut Segment) -> Result<()> {
+        if active.size() >= self.options.segment_size {
+            // the segment must be recorded before anything is written to it
+            let output = self.full_path.join(Segment::gen_name());
+            let new = Segment::new(&self.full_path)?;
+            self.manifest.lock().unwrap().push(new.path())?;
+            let old = mem::replace(active, new);
+            old.seal()?;
+            self.compact(output, active.path().clone());
+        }
         Ok(())
     }
 
-    fn compact(&mut self) -> Result<()> {
-        let segments = Self::list_segments(&self.full_path)?;
+    /// write tombstones for the keys which expired by now, a batch at a time
+    fn sweep(&self) -> Result<()> {
+        loop {
+            // holding the writer lock, expired keys cannot be written in between
+            let mut active = self.active.lock().unwrap();
+            let expired = self
+                .memtbl
+                .read()
+                .unwrap()
+                .expired(self.time.now_millis(), EXPIRE_BATCH);
+            if expired.is_empty() {
+                return Ok(());
+            }
+            let mut tombstones = Vec::with_capacity(expired.len());
+            for key in &expired {
+                tombstones.push(active.remove(key)?);
+            }
+            self.finish(&mut active, |memtbl| {
+                for (key, tombstone) in expired.iter().zip(tombstones) {
+                    memtbl.remove(key, tombstone);
+                }
+            })?;
+            if expired.len() < EXPIRE_BATCH {
+                return Ok(());
+            }
+        }
+    }
 
-        self.active.borrow().flush()?;
-        self.set_count = 0;
+    /// write the batch as a single record and index it, the caller holds the writer lock
+    fn apply(&self, active: &mut Segment, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        {
+            // keys may be set or removed by earlier operations of the batch
+            let memtbl = self.memtbl.read().unwrap();
+            let mut exists = HashMap::new();
+            for op in batch.ops() {
+                match op {
+                    Op::Set(key, _) | Op::SetWithTtl(key, _, _) => {
+                        exists.insert(key, true);
+                    }
+                    Op::Rm(key) => {
+                        let found = match exists.get(key) {
+                            Some(&found) => found,
+                            None => memtbl.contains(key),
+                        };
+                        if !found {
+                            return Err(Error::from(ErrorKind::KeyNotExist));
+                        }
+                        exists.insert(key, false);
+                    }
+                }
+            }
+        }
 
-        let mut store = Self::new(&self.full_path)?;
-        for key in self.memtbl.map.keys() {
-            if let Some(value) = self.get_no_mut(key.to_owned())? {
-                store.set_no_compact(key.to_owned(), value)?;
-                if store.active.borrow().size() > SEGMENT_SIZE_THRESHOLD {
-                    let old = store.active.replace(Segment::new(&self.full_path)?);
-                    drop(old);
+        let entries: Vec<_> = batch
+            .into_ops()
+            .into_iter()
+            .map(|op| match op {
+                Op::Set(key, value) => log::Entry::Set(key, value),
+                Op::Rm(key) => log::Entry::Rm(key),
+                Op::SetWithTtl(key, value, ttl) => {
+                    let expires = self.time.deadline(Duration::from_millis(ttl));
+                    log::Entry::SetEx(key, value, expires)
                 }
+            })
+            .collect();
+        let size = active.size();
+        let pointers = active.batch(&entries)?;
+        let framing = active.size() - size - pointers.iter().map(|p| p.len()).sum::<u64>();
+        let path = active.path().clone();
+        self.finish(active, |memtbl| {
+            memtbl.waste(&path, framing);
+            for (entry, pointer) in entries.into_iter().zip(pointers) {
+                match entry {
+                    log::Entry::Set(key, _) => memtbl.insert(key, pointer, None),
+                    log::Entry::SetEx(key, _, expires) => {
+                        memtbl.insert(key, pointer, Some(expires))
+                    }
+                    log::Entry::Rm(key) => memtbl.remove(&key, pointer),
+                    log::Entry::Batch(_) => unreachable!("batches are not nested"),
+                }
+            }
+        })
+    }
+
+    /// hand sealed segments worth compacting over to the background compactor
+    ///
+    /// a segment is picked when its garbage ratio exceeds the threshold,
+    /// segments with little live data are merged as well when there are several of them
+    fn compact(&self, output: PathBuf, active: PathBuf) {
+        if self.compactor.is_busy() {
+            return;
+        }
+        // the output takes the place right before the active segment,
+        // so anything written while compacting takes precedence on replay
+        let segments = self.manifest.lock().unwrap().segments();
+        let memtbl = self.memtbl.read().unwrap();
+        let sealed: Vec<_> = segments
+            .into_iter()
+            .filter(|seg| *seg != active)
+            .filter_map(|seg| memtbl.usage.get(&seg).map(|usage| (seg, *usage)))
+            .collect();
+        drop(memtbl);
+
+        let small = |usage: &Usage| usage.total - usage.dead < self.options.segment_size / 4;
+        let merge_small = sealed.iter().filter(|(_, usage)| small(usage)).count() > 1;
+        let mut inputs = Vec::new();
+        let mut tombstone_free = None;
+        for (seg, usage) in sealed {
+            if usage.garbage_ratio() > self.options.garbage_ratio || (merge_small && small(&usage))
+            {
+                inputs.push(seg);
+            } else if tombstone_free.is_none() {
+                tombstone_free = Some(inputs.len());
+            }
+        }
+        if inputs.is_empty() {
+            return;
+        }
+        let tombstone_free = tombstone_free.unwrap_or(inputs.len());
+        self.compactor
+            .compact(inputs, tombstone_free, output, active);
+    }
+}
+
+/// bits of a version counting the writes within one epoch
+const EPOCH_SHIFT: u32 = 40;
+
+impl MemTable {
+    /// an empty index whose versions belong to the given epoch
+    pub(crate) fn new(epoch: u64, time: Clock) -> Self {
+        Self {
+            clock: epoch << EPOCH_SHIFT,
+            time,
+            ..Self::default()
+        }
+    }
+
+    /// replay the index of a segment on top of the current one
+    pub(crate) fn load(&mut self, path: &PathBuf, hint: &log::Hint) {
+        // records overwritten within the segment and tombstones are garbage from the start
+        let live: u64 = hint.offset().values().map(|&(_, len)| len).sum();
+        self.usage.insert(
+            path.clone(),
+            Usage {
+                total: hint.size(),
+                dead: hint.size() - live,
+            },
+        );
+        for key in hint.count().keys() {
+            let old = if let Some(&(offset, len)) = hint.offset().get(key) {
+                let pointer = log::Pointer::new(path, offset, len);
+                let slot = self.slot(key, pointer, hint.expires().get(key).copied());
+                self.map.insert(key.clone(), slot)
+            } else {
+                self.map.remove(key)
+            };
+            if let Some(old) = old {
+                self.kill(key, &old);
+            }
+        }
+    }
+
+    /// point the key to a newly written record, which expires at the given time if any
+    pub(crate) fn insert(&mut self, key: Vec<u8>, pointer: log::Pointer, expires: Option<u64>) {
+        self.usage.entry(pointer.path().clone()).or_default().total += pointer.len();
+        let slot = self.slot(&key, pointer, expires);
+        let seq = slot.version;
+        match self.map.get_mut(&key) {
+            Some(current) => {
+                let old = mem::replace(current, slot);
+                self.kill(&key, &old);
+                self.shadow(key, old, seq);
+            }
+            None => {
+                self.map.insert(key, slot);
             }
         }
+    }
 
-        let old = self.active.replace(Segment::new(&self.full_path)?);
-        drop(old);
-        std::mem::swap(&mut self.memtbl, &mut store.memtbl);
-        drop(store);
+    /// drop the key whose tombstone has been written to the given place
+    pub(crate) fn remove(&mut self, key: &[u8], tombstone: log::Pointer) {
+        let usage = self.usage.entry(tombstone.path().clone()).or_default();
+        usage.total += tombstone.len();
+        usage.dead += tombstone.len();
+        self.clock += 1;
+        if let Some((key, old)) = self.map.remove_entry(key) {
+            self.kill(&key, &old);
+            self.shadow(key, old, self.clock);
+        }
+    }
 
-        for mut file in segments {
-            file.set_extension(LOG_FILE_EXT);
-            fs::remove_file(&file)?;
-            file.set_extension(HINT_FILE_EXT);
-            fs::remove_file(&file)?;
+    /// drop an expired key whose record compaction left behind,
+    /// snapshots taken before it expired still see it
+    pub(crate) fn drop_expired(&mut self, key: &[u8]) {
+        self.clock += 1;
+        if let Some((key, old)) = self.map.remove_entry(key) {
+            self.kill(&key, &old);
+            self.shadow(key, old, self.clock);
         }
+    }
+
+    /// whether the key exists and has not expired
+    pub(crate) fn contains(&self, key: &[u8]) -> bool {
+        self.slot_at(key, None).is_some()
+    }
+
+    /// the current version of a key, 0 if it does not exist
+    pub(crate) fn version(&self, key: &[u8]) -> u64 {
+        self.slot_at(key, None).map_or(0, |slot| slot.version)
+    }
 
+    /// up to `limit` keys which expired by `now`, the earliest first
+    fn expired(&self, now: u64, limit: usize) -> Vec<Vec<u8>> {
+        self.expiring
+            .iter()
+            .take_while(|(expires, _)| *expires <= now)
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+
+    /// index entry for a newly written record, with a version no key had before
+    fn slot(&mut self, key: &[u8], pointer: log::Pointer, expires: Option<u64>) -> Slot {
+        self.clock += 1;
+        if let Some(expires) = expires {
+            self.expiring.insert((expires, key.to_vec()));
+        }
+        Slot {
+            pointer,
+            version: self.clock,
+            expires,
+        }
+    }
+
+    /// keep a value replaced at `seq` if a snapshot taken before still sees it
+    fn shadow(&mut self, key: Vec<u8>, slot: Slot, seq: u64) {
+        if self.snapshots.range(slot.version..seq).next().is_none() {
+            return;
+        }
+        *self.pins.entry(slot.pointer.path().clone()).or_default() += 1;
+        let replaced = Shadowed {
+            replaced: seq,
+            slot,
+        };
+        self.history.entry(key).or_default().push(replaced);
+    }
+
+    /// the slot of a key as seen by the snapshot, the latest one if `None`
+    /// expiry is judged by the time the snapshot was taken
+    fn slot_at(&self, key: &[u8], pin: Option<&Pin>) -> Option<&Slot> {
+        let now = pin.map_or_else(|| self.time.now_millis(), |pin| pin.taken);
+        self.slot_at_unexpired(key, pin.map(|pin| pin.seq))
+            .filter(|slot| !utils::is_expired_at(slot.expires, now))
+    }
+
+    fn slot_at_unexpired(&self, key: &[u8], seq: Option<u64>) -> Option<&Slot> {
+        let current = self.map.get(key);
+        let seq = match seq {
+            Some(seq) => seq,
+            None => return current,
+        };
+        // the first value replaced after the snapshot is the one it sees,
+        // unless the key did not exist yet back then
+        let replaced = self
+            .history
+            .get(key)
+            .and_then(|history| history.iter().find(|shadowed| shadowed.replaced > seq));
+        match replaced {
+            Some(shadowed) => Some(&shadowed.slot).filter(|slot| slot.version <= seq),
+            None => current.filter(|slot| slot.version <= seq),
+        }
+    }
+
+    /// up to `limit` keys in the range which may have had a value as of the sequence number
+    fn keys(
+        &self,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        seq: Option<u64>,
+        limit: usize,
+    ) -> Vec<Vec<u8>> {
+        let mut keys: Vec<_> = self
+            .map
+            .range(range.clone())
+            .take(limit)
+            .map(|(key, _)| key)
+            .collect();
+        if seq.is_some() {
+            // keys removed since are only found in the history
+            keys.extend(self.history.range(range).take(limit).map(|(key, _)| key));
+            keys.sort();
+            keys.dedup();
+            keys.truncate(limit);
+        }
+        keys.into_iter().cloned().collect()
+    }
+
+    /// register a snapshot of the current state, return its sequence number
+    fn pin(&mut self) -> u64 {
+        *self.snapshots.entry(self.clock).or_default() += 1;
+        self.clock
+    }
+
+    /// forget a snapshot and the values only it could see,
+    /// return the retired segments which are no longer needed
+    fn unpin(&mut self, seq: u64) -> Vec<PathBuf> {
+        match self.snapshots.get_mut(&seq) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                return Vec::new();
+            }
+            _ => {
+                self.snapshots.remove(&seq);
+            }
+        }
+        let (snapshots, pins) = (&self.snapshots, &mut self.pins);
+        self.history.retain(|_, history| {
+            history.retain(|shadowed| {
+                let seen = snapshots
+                    .range(shadowed.slot.version..shadowed.replaced)
+                    .next();
+                if seen.is_none() {
+                    let path = shadowed.slot.pointer.path();
+                    match pins.get_mut(path) {
+                        Some(count) if *count > 1 => *count -= 1,
+                        _ => {
+                            pins.remove(path);
+                        }
+                    }
+                }
+                seen.is_some()
+            });
+            !history.is_empty()
+        });
+        let free: Vec<_> = self
+            .retired
+            .iter()
+            .filter(|path| !self.pins.contains_key(*path))
+            .cloned()
+            .collect();
+        for path in &free {
+            self.retired.remove(path);
+        }
+        free
+    }
+
+    /// whether a snapshot may still read from the segment
+    pub(crate) fn is_pinned(&self, path: &Path) -> bool {
+        self.pins.contains_key(path)
+    }
+
+    /// keep compacted segments around until the snapshots reading them are released
+    pub(crate) fn retire(&mut self, paths: Vec<PathBuf>) {
+        self.retired.extend(paths);
+    }
+
+    /// account bytes written to the segment which never hold live data
+    pub(crate) fn waste(&mut self, path: &Path, len: u64) {
+        let usage = self.usage.entry(path.to_path_buf()).or_default();
+        usage.total += len;
+        usage.dead += len;
+    }
+
+    /// account a record which is no longer referenced as garbage
+    pub(crate) fn kill_record(&mut self, pointer: &log::Pointer) {
+        if let Some(usage) = self.usage.get_mut(pointer.path()) {
+            usage.dead += pointer.len();
+        }
+    }
+
+    /// account the record of a slot the key no longer has as garbage
+    fn kill(&mut self, key: &[u8], slot: &Slot) {
+        // the slot replacing it may expire at the very same time
+        let current = self.map.get(key).and_then(|slot| slot.expires);
+        if let Some(expires) = slot.expires.filter(|&expires| Some(expires) != current) {
+            self.expiring.remove(&(expires, key.to_vec()));
+        }
+        self.kill_record(&slot.pointer);
+    }
+}
+
+impl Usage {
+    /// fraction of the segment which is no longer referenced
+    pub(crate) fn garbage_ratio(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.dead as f64 / self.total as f64
+        }
+    }
+}
+
+impl Readers {
+    /// read the entry the pointer refers to
+    /// the caller must hold the index lock, which keeps the compactor from deleting the file
+    fn read(&self, pointer: &log::Pointer, memtbl: &MemTable) -> Result<log::Entry> {
+        let mut files = self.files.borrow_mut();
+        if !files.contains_key(pointer.path()) {
+            // forget about files removed by compaction in the meantime
+            files.retain(|path, _| memtbl.usage.contains_key(path));
+            let file = fs::File::open(pointer.path())?;
+            files.insert(pointer.path().clone(), file);
+        }
+        pointer.read(&files[pointer.path()])
+    }
+}
+
+impl StoreScan {
+    fn new(store: KvStore, pin: Option<Arc<Pin>>, range: impl RangeBounds<Vec<u8>>) -> Self {
+        let owned = |bound: Bound<&Vec<u8>>| match bound {
+            Bound::Included(key) => Bound::Included(key.clone()),
+            Bound::Excluded(key) => Bound::Excluded(key.clone()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let (start, end) = (owned(range.start_bound()), owned(range.end_bound()));
+        // `BTreeMap::range` panics on inverted ranges, they are empty anyway
+        let done = match (&start, &end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            _ => false,
+        };
+        Self {
+            store,
+            pin,
+            start,
+            end,
+            batch: VecDeque::new(),
+            done,
+        }
+    }
+
+    fn fill(&mut self) -> Result<()> {
+        let memtbl = self.store.shared.memtbl.read().unwrap();
+        let pin = self.pin.as_deref();
+        let range = (self.start.clone(), self.end.clone());
+        let keys = memtbl.keys(range, pin.map(|pin| pin.seq), SCAN_BATCH);
+        for key in &keys {
+            let slot = match memtbl.slot_at(key, pin) {
+                Some(slot) => slot,
+                None => continue,
+            };
+            match self.store.readers.read(&slot.pointer, &memtbl)? {
+                log::Entry::Set(_, value) | log::Entry::SetEx(_, value, _) => {
+                    self.batch.push_back((key.clone(), value))
+                }
+                _ => return Err(Error::from(ErrorKind::InvalidLogEntry)),
+            }
+        }
+        match keys.last() {
+            Some(key) if keys.len() == SCAN_BATCH => self.start = Bound::Excluded(key.clone()),
+            _ => self.done = true,
+        }
         Ok(())
     }
 }
 
+impl Iterator for StoreScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // a batch may come up empty when its keys did not exist in the snapshot
+        while self.batch.is_empty() && !self.done {
+            if let Err(e) = self.fill() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.batch.pop_front().map(Ok)
+    }
+}
+
+impl Clone for Readers {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            segment_size: SEGMENT_SIZE_THRESHOLD,
+            garbage_ratio: GARBAGE_RATIO_THRESHOLD,
+            durability: Durability::Os,
+        }
+    }
+}
+
 impl Default for MemTable {
     fn default() -> Self {
         Self {
-            map: HashMap::new(),
+            map: BTreeMap::new(),
+            usage: HashMap::new(),
+            clock: 0,
+            history: BTreeMap::new(),
+            snapshots: BTreeMap::new(),
+            pins: HashMap::new(),
+            retired: HashSet::new(),
+            expiring: BTreeSet::new(),
+            time: Clock::system(),
         }
     }
 }
@@ -137,64 +990,200 @@ impl Default for MemTable {
 impl KvsEngine for KvStore {
     /// Open the KvStore at a given path. Return the KvStore.
     fn open(dir: impl Into<PathBuf>) -> Result<Self> {
-        let dir = dir.into();
-        let segments = Self::list_segments(&dir)?;
-        if segments.is_empty() {
-            return Self::new(dir);
-        }
-
-        let mut memtbl = MemTable::default();
-        for seg in segments {
-            let active = Segment::open(seg)?;
-            for key in active.hint().count().keys() {
-                if let Some(offset) = active.hint().offset().get(key) {
-                    let pointer = log::Pointer::new(active.path(), *offset);
-                    memtbl.map.insert(key.clone(), pointer);
-                } else {
-                    memtbl.map.remove(key);
-                }
+        Self::open_with(dir, KvStoreOptions::default())
+    }
+
+    /// Set the value of a key
+    ///
+    /// Return an error if the value is not written successfully.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut active = self.shared.active.lock().unwrap();
+        let pointer = active.set(key.clone(), value)?;
+        self.shared
+            .finish(&mut active, |memtbl| memtbl.insert(key, pointer, None))
+    }
+
+    /// Set the value of a key which expires once the time to live has passed.
+    ///
+    /// Expired keys are skipped by reads right away and removed by a background sweep.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let mut active = self.shared.active.lock().unwrap();
+        let expires = self.shared.time.deadline(ttl);
+        let pointer = active.set_with_expiry(key.clone(), value, Some(expires))?;
+        self.shared.finish(&mut active, |memtbl| {
+            memtbl.insert(key, pointer, Some(expires))
+        })
+    }
+
+    /// Make an existing key expire once the time to live has passed.
+    ///
+    /// The value is written again along with its new expiry.
+    fn expire(&self, key: Vec<u8>, ttl: Duration) -> Result<bool> {
+        let mut active = self.shared.active.lock().unwrap();
+        match self.get_bytes(key.clone())? {
+            Some(value) => {
+                let expires = self.shared.time.deadline(ttl);
+                let pointer = active.set_with_expiry(key.clone(), value, Some(expires))?;
+                self.shared.finish(&mut active, |memtbl| {
+                    memtbl.insert(key, pointer, Some(expires))
+                })?;
+                Ok(true)
             }
+            None => Ok(false),
         }
-        let active = Segment::new(dir.clone())?;
-        Ok(Self {
-            full_path: dir,
-            active: RefCell::new(active),
-            memtbl,
-            set_count: 0,
-        })
     }
 
-    /// Set the value of a string key to a string
+    /// Get the time the key has left to live, `None` if it does not expire.
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let memtbl = self.shared.memtbl.read().unwrap();
+        match memtbl.slot_at(&key, None) {
+            Some(slot) => Ok(slot.expires.map(|expires| {
+                Duration::from_millis(expires.saturating_sub(memtbl.time.now_millis()))
+            })),
+            None => Err(Error::from(ErrorKind::KeyNotExist)),
+        }
+    }
+
+    /// Keep the key from expiring.
     ///
-    /// Return an error if the value is not written successfully.
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.set_no_compact(key, value)?;
-        self.set_count += 1;
-        if self.set_count > COMPACTION_THRESHOLD {
-            self.compact()?;
+    /// The value is written again without an expiry if it had one.
+    fn persist(&self, key: Vec<u8>) -> Result<bool> {
+        let mut active = self.shared.active.lock().unwrap();
+        let expiring = self
+            .shared
+            .memtbl
+            .read()
+            .unwrap()
+            .slot_at(&key, None)
+            .and_then(|slot| slot.expires)
+            .is_some();
+        if !expiring {
+            return Ok(false);
         }
-        Ok(())
+        let value = match self.get_bytes(key.clone())? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        let pointer = active.set(key.clone(), value)?;
+        self.shared
+            .finish(&mut active, |memtbl| memtbl.insert(key, pointer, None))?;
+        Ok(true)
     }
 
-    /// Get the string value of the a string key.
+    /// Get the value of a key.
     ///
     /// If the key does not exist, return `None`.
     /// Return an error if the value is not read successfully.
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        self.get_no_mut(key)
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(key)?.0)
+    }
+
+    /// Get the value of a key along with its version.
+    ///
+    /// Versions are kept in the index, each time the store is opened
+    /// they continue from a new epoch so no version is handed out twice.
+    fn get_versioned(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        self.read_at(&key, None)
+    }
+
+    /// Set the key to `new` if its value is still `expected`.
+    ///
+    /// The writer lock is held from the comparison to the write.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let mut active = self.shared.active.lock().unwrap();
+        let current = self.get_bytes(key.clone())?;
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => {
+                let pointer = active.set(key.clone(), value)?;
+                self.shared
+                    .finish(&mut active, |memtbl| memtbl.insert(key, pointer, None))?;
+            }
+            None if current.is_some() => {
+                let tombstone = active.remove(&key)?;
+                self.shared
+                    .finish(&mut active, |memtbl| memtbl.remove(&key, tombstone))?;
+            }
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// Set the value of a key only if the key exists.
+    ///
+    /// The writer lock is held from checking the key to writing the value.
+    fn set_if_present(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        let mut active = self.shared.active.lock().unwrap();
+        if self
+            .shared
+            .memtbl
+            .read()
+            .unwrap()
+            .slot_at(&key, None)
+            .is_none()
+        {
+            return Ok(false);
+        }
+        let pointer = active.set(key.clone(), value)?;
+        self.shared
+            .finish(&mut active, |memtbl| memtbl.insert(key, pointer, None))?;
+        Ok(true)
+    }
+
+    /// Apply all operations of the batch atomically.
+    ///
+    /// The batch is written as a single record, which is recovered entirely or not at all.
+    /// If a removed key does not exist, return an error and apply nothing.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut active = self.shared.active.lock().unwrap();
+        self.shared.apply(&mut active, batch)
+    }
+
+    /// Apply the writes of the transaction atomically if none of its watched keys changed.
+    ///
+    /// The writer lock is held from checking the versions to writing the batch.
+    fn commit(&self, txn: Transaction) -> Result<()> {
+        let mut active = self.shared.active.lock().unwrap();
+        {
+            let memtbl = self.shared.memtbl.read().unwrap();
+            for (key, version) in txn.watched() {
+                if memtbl.version(key) != *version {
+                    return Err(Error::new(
+                        ErrorKind::Conflict,
+                        format!("{} changed", String::from_utf8_lossy(key)),
+                    ));
+                }
+            }
+        }
+        self.shared.apply(&mut active, txn.into_writes())
+    }
+
+    /// Iterate over the pairs whose key lies in the range, in ascending key order.
+    ///
+    /// The iterator has its own handle of the store and reads a batch of pairs at a time,
+    /// writes made while iterating show up unless their key has been passed already.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Scan> {
+        Ok(Box::new(StoreScan::new(self.clone(), None, range)))
     }
 
     /// Remove a given key.
     ///
     /// Return an error if the key does not exist or is not removed successfully.
-    fn remove(&mut self, key: String) -> Result<()> {
-        match self.memtbl.map.get(&key) {
-            None => Err(Error::from(ErrorKind::KeyNotExist)),
-            Some(_) => {
-                self.active.borrow_mut().remove(&key)?;
-                self.memtbl.map.remove(&key);
-                Ok(())
-            }
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        // holding the writer lock, nobody else can bring the key back in between
+        let mut active = self.shared.active.lock().unwrap();
+        if !self.shared.memtbl.read().unwrap().contains(&key) {
+            return Err(Error::from(ErrorKind::KeyNotExist));
         }
+        let tombstone = active.remove(&key)?;
+        self.shared
+            .finish(&mut active, |memtbl| memtbl.remove(&key, tombstone))
     }
 }
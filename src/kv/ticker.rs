@@ -0,0 +1,53 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::error;
+
+use crate::Result;
+
+/// handle to a background thread running a task at a fixed interval,
+/// such as removing expired keys or syncing the active segment
+///
+/// The task runs until the handle is dropped, failures are logged.
+#[derive(Debug)]
+pub(crate) struct Ticker {
+    /// never sent on, dropping it stops the thread
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Ticker {
+    pub fn new(
+        name: &str,
+        interval: Duration,
+        mut task: impl FnMut() -> Result<()> + Send + 'static,
+    ) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<()>();
+        let what = name.to_owned();
+        let handle = thread::Builder::new()
+            .name(format!("kvs-{}", name))
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                    if let Err(e) = task() {
+                        error!("{} failed: {}", what, e);
+                    }
+                }
+            })?;
+        Ok(Self {
+            sender: Some(sender),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("background thread panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::batch::WriteBatch;
+
+/// writes committed by `KvsEngine::commit` only if the keys read before are unchanged
+///
+/// Keys are read through `KvsEngine::get_watched`, which records the version seen.
+/// The writes are applied atomically like a `WriteBatch`, the reads of a transaction
+/// do not see its own writes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Transaction {
+    /// keys and the versions they must still have at commit
+    watched: Vec<(Vec<u8>, u64)>,
+    writes: WriteBatch,
+}
+
+impl Transaction {
+    /// create an empty transaction
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// fail the commit unless the key still has the given version,
+    /// as returned by `KvsEngine::get_versioned`
+    pub fn watch(&mut self, key: impl Into<Vec<u8>>, version: u64) -> &mut Self {
+        self.watched.push((key.into(), version));
+        self
+    }
+
+    /// set the value of a key at commit
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.writes.set(key, value);
+        self
+    }
+
+    /// set the value of a key at commit, which expires once the time to live has passed
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> &mut Self {
+        self.writes.set_with_ttl(key, value, ttl);
+        self
+    }
+
+    /// remove a key at commit, the commit fails if it does not exist at this point
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.writes.remove(key);
+        self
+    }
+
+    pub(crate) fn watched(&self) -> &[(Vec<u8>, u64)] {
+        &self.watched
+    }
+
+    pub(crate) fn into_writes(self) -> WriteBatch {
+        self.writes
+    }
+}
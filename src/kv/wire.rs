@@ -0,0 +1,257 @@
+//! The framed protocol spoken between `KvsClient` and `KvsServer`.
+//!
+//! Every message is a frame of a big endian `u32` length followed by that many bytes of
+//! bincode. The client opens with `utils::Hello` carrying its protocol version,
+//! the server answers with `utils::Welcome` and closes the connection unless it speaks
+//! the same version. Afterwards each `utils::Request` frame is answered by one
+//! `utils::Respond` frame. A request the server fails to decode is answered with an error
+//! while the connection stays usable. Errors carry the stable code of their `ErrorKind`.
+//! Scans are paged, every `Respond::Scan` names the key the next page starts at.
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::config::{MAX_FRAME_LEN, MAX_HANDSHAKE_FRAME_LEN, MAX_SCAN_COUNT, PROTOCOL_VERSION};
+use crate::{utils, Error, ErrorKind, KvsEngine, Result};
+
+/// state of one connection
+#[derive(Debug, Default)]
+pub(crate) struct Session {
+    /// whether the client said hello, nothing else is accepted before
+    greeted: bool,
+}
+
+/// read one frame from the connection and write back the answer
+/// return `false` once the connection should be closed
+pub(crate) fn serve<T: KvsEngine>(
+    engine: &T,
+    session: &mut Session,
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+) -> Result<bool> {
+    let limit = if session.greeted {
+        MAX_FRAME_LEN
+    } else {
+        MAX_HANDSHAKE_FRAME_LEN
+    };
+    let frame = read_frame(reader, limit)?;
+    if !session.greeted {
+        let hello: utils::Hello = decode(&frame)?;
+        let welcome = welcome(&hello);
+        write_frame(writer, &welcome)?;
+        return match welcome {
+            utils::Welcome::Accepted => {
+                session.greeted = true;
+                Ok(true)
+            }
+            utils::Welcome::Rejected(_) => Ok(false),
+        };
+    }
+    let res = match decode(&frame).and_then(|req| process(engine, req)) {
+        Ok(res) => res,
+        Err(e) => {
+            let (code, message) = e.to_wire();
+            utils::Respond::Error { code, message }
+        }
+    };
+    write_frame(writer, &res)?;
+    Ok(true)
+}
+
+/// accept clients speaking the version of the server
+fn welcome(hello: &utils::Hello) -> utils::Welcome {
+    info!("client hello version {}", hello.version);
+    if hello.version != PROTOCOL_VERSION {
+        return utils::Welcome::Rejected(format!(
+            "protocol version {} is not supported, expected {}",
+            hello.version, PROTOCOL_VERSION
+        ));
+    }
+    utils::Welcome::Accepted
+}
+
+/// run a single request against the engine
+fn process<T: KvsEngine>(engine: &T, req: utils::Request) -> Result<utils::Respond> {
+    match req {
+        utils::Request::Get(key) => {
+            info!("incoming request GET {}", String::from_utf8_lossy(&key));
+            engine.get_bytes(key).map(utils::Respond::Ok)
+        }
+        utils::Request::Set(key, value) => {
+            info!(
+                "incoming request SET {} {}",
+                String::from_utf8_lossy(&key),
+                String::from_utf8_lossy(&value)
+            );
+            engine
+                .set_bytes(key, value)
+                .map(|_| utils::Respond::Ok(None))
+        }
+        utils::Request::Rm(key) => {
+            info!("incoming request RM {}", String::from_utf8_lossy(&key));
+            engine.remove_bytes(key).map(|_| utils::Respond::Ok(None))
+        }
+        utils::Request::Scan { start, end, count } => {
+            info!(
+                "incoming request SCAN {} {:?} {}",
+                String::from_utf8_lossy(&start),
+                end.as_ref().map(|end| String::from_utf8_lossy(end)),
+                count
+            );
+            scan(engine, start, end, count)
+        }
+        utils::Request::Batch(batch) => {
+            info!("incoming request BATCH of {} operations", batch.len());
+            engine.write_batch(batch).map(|_| utils::Respond::Ok(None))
+        }
+        utils::Request::Cas { key, expected, new } => {
+            info!("incoming request CAS {}", String::from_utf8_lossy(&key));
+            engine
+                .compare_and_swap(key, expected, new)
+                .map(utils::Respond::Swapped)
+        }
+        utils::Request::SetIfAbsent(key, value) => {
+            info!("incoming request SETNX {}", String::from_utf8_lossy(&key));
+            engine
+                .set_if_absent(key, value)
+                .map(utils::Respond::Swapped)
+        }
+        utils::Request::SetIfPresent(key, value) => {
+            info!("incoming request SETXX {}", String::from_utf8_lossy(&key));
+            engine
+                .set_if_present(key, value)
+                .map(utils::Respond::Swapped)
+        }
+        utils::Request::GetVersioned(key) => {
+            info!("incoming request GETV {}", String::from_utf8_lossy(&key));
+            engine
+                .get_versioned(key)
+                .map(|(value, version)| utils::Respond::Versioned { value, version })
+        }
+        utils::Request::Commit(txn) => {
+            info!("incoming request COMMIT");
+            engine.commit(txn).map(|_| utils::Respond::Ok(None))
+        }
+        utils::Request::SetWithTtl(key, value, ttl) => {
+            info!(
+                "incoming request SET {} {} PX {}",
+                String::from_utf8_lossy(&key),
+                String::from_utf8_lossy(&value),
+                ttl
+            );
+            engine
+                .set_with_ttl(key, value, Duration::from_millis(ttl))
+                .map(|_| utils::Respond::Ok(None))
+        }
+        utils::Request::Expire(key, ttl) => {
+            info!(
+                "incoming request PEXPIRE {} {}",
+                String::from_utf8_lossy(&key),
+                ttl
+            );
+            engine
+                .expire(key, Duration::from_millis(ttl))
+                .map(utils::Respond::Swapped)
+        }
+        utils::Request::Ttl(key) => {
+            info!("incoming request PTTL {}", String::from_utf8_lossy(&key));
+            engine
+                .ttl(key)
+                .map(|ttl| utils::Respond::Ttl(ttl.map(|ttl| ttl.as_millis() as u64)))
+        }
+        utils::Request::Persist(key) => {
+            info!("incoming request PERSIST {}", String::from_utf8_lossy(&key));
+            engine.persist(key).map(utils::Respond::Swapped)
+        }
+    }
+}
+
+/// one page of a scan, continued from the successor of its last key
+fn scan<T: KvsEngine>(
+    engine: &T,
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+    count: u32,
+) -> Result<utils::Respond> {
+    let count = count.clamp(1, MAX_SCAN_COUNT) as usize;
+    let pairs = match end {
+        Some(ref end) if *end <= start => Vec::new(),
+        Some(end) => engine
+            .scan(start..end)?
+            .take(count)
+            .collect::<Result<_>>()?,
+        None => engine.scan(start..)?.take(count).collect::<Result<_>>()?,
+    };
+    let next = match pairs.last() {
+        Some((key, _)) if pairs.len() == count => Some(utils::successor(key)),
+        _ => None,
+    };
+    Ok(utils::Respond::Scan { pairs, next })
+}
+
+/// write the message as one frame
+pub(crate) fn write_frame(writer: &mut impl Write, msg: &impl Serialize) -> Result<()> {
+    writer.write_all(&encode_frame(msg)?)?;
+    Ok(())
+}
+
+/// serialize the message into one frame, length prefix included
+pub(crate) fn encode_frame(msg: &impl Serialize) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(msg)?;
+    if payload.len() > MAX_FRAME_LEN as usize {
+        return Err(Error::new(ErrorKind::Protocol, "frame too large"));
+    }
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// read the payload of one frame, which must not be longer than `limit`
+///
+/// The payload buffer grows as bytes arrive rather than trusting the announced length.
+pub(crate) fn read_frame(reader: &mut impl Read, limit: u32) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > limit {
+        return Err(Error::new(
+            ErrorKind::Protocol,
+            format!("frame of {} bytes is too large", len),
+        ));
+    }
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len as usize {
+        return Err(Error::from(io::Error::from(io::ErrorKind::UnexpectedEof)));
+    }
+    Ok(payload)
+}
+
+/// decode the payload of a frame, which must be used up completely
+pub(crate) fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T> {
+    let mut cursor = payload;
+    let msg = bincode::deserialize_from(&mut cursor)
+        .map_err(|e| Error::new(ErrorKind::Protocol, format!("unsupported message: {}", e)))?;
+    if !cursor.is_empty() {
+        return Err(Error::new(ErrorKind::Protocol, "trailing bytes in frame"));
+    }
+    Ok(msg)
+}
+
+/// the handshake of a client
+pub(crate) fn hello(reader: &mut impl Read, writer: &mut impl Write) -> Result<()> {
+    let hello = utils::Hello {
+        version: PROTOCOL_VERSION,
+    };
+    write_frame(writer, &hello)?;
+    writer.flush()?;
+    match decode(&read_frame(reader, MAX_HANDSHAKE_FRAME_LEN)?)? {
+        utils::Welcome::Accepted => Ok(()),
+        utils::Welcome::Rejected(reason) => Err(Error::new(ErrorKind::Protocol, reason)),
+    }
+}
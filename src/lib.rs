@@ -3,40 +3,144 @@
 
 //! A key-value store
 
+use std::ops::{Bound, RangeBounds};
 use std::path::PathBuf;
+use std::time::Duration;
 
 pub use error::{Error, ErrorKind, Result};
-pub use kv::client::KvsClient;
-pub use kv::server::KvsServer;
-pub use kv::store::KvStore;
-pub use resp::Resp;
+pub use kv::batch::WriteBatch;
+pub use kv::client::{KvsClient, Pipeline};
+#[cfg(feature = "testing")]
+pub use kv::clock::Clock;
+pub use kv::server::{KvsServer, Protocol};
+pub use kv::sled::SledKvsEngine;
+pub use kv::store::{Durability, KvStore, KvStoreOptions, Snapshot};
+pub use kv::transaction::Transaction;
+pub use resp::{Resp, RespDecoder};
+pub use thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
 
 mod config;
 mod error;
 mod kv;
 mod log;
-mod resp;
+pub mod resp;
+mod thread_pool;
 /// helpers
 pub mod utils;
 
+/// key value pairs in ascending key order, as returned by `KvsEngine::scan`
+pub type Scan = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>;
+
 /// Kvs pluggable backend interface
-pub trait KvsEngine
-where
-    Self: Sized,
-{
+///
+/// Handles are cheap to clone and all of them operate on the same database,
+/// so one can be handed to every thread of a server.
+pub trait KvsEngine: Clone + Send + 'static {
     /// Open database at given data directory
     fn open(dir: impl Into<PathBuf>) -> Result<Self>;
 
+    /// Set the value of a key to the given bytes.
+    /// Return an error if the value is not written successfully.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Get the value of a key.
+    /// If the key does not exist, return `None`.
+    /// Return an error if the value is not read successfully.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Remove a given key.
+    /// Return an error if the key does not exit or value is not read successfully.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Set the value of a key which expires once the time to live has passed.
+    /// Expired keys are no longer seen and eventually removed.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Make an existing key expire once the time to live has passed.
+    /// Return whether the key exists.
+    fn expire(&self, key: Vec<u8>, ttl: Duration) -> Result<bool>;
+
+    /// Get the time the key has left to live, `None` if it does not expire.
+    /// Return an error if the key does not exist.
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    /// Keep the key from expiring.
+    /// Return whether the key existed and had a time to live.
+    fn persist(&self, key: Vec<u8>) -> Result<bool>;
+
+    /// Set the key to `new` if its value is still `expected`, `None` meaning the key is absent.
+    /// A `new` of `None` removes the key.
+    /// Like any other set, the swapped value drops the time to live the key had.
+    /// Return whether the value was swapped, which happens atomically with the comparison.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Set the value of a key which does not exist yet.
+    /// Return whether the value was set.
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Set the value of a key only if the key exists, dropping the time to live it had.
+    /// Return whether the value was set, which happens atomically with the check.
+    fn set_if_present(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool>;
+
+    /// Get the value of a key along with its version.
+    /// The version changes whenever the key is written, 0 means the key does not exist.
+    fn get_versioned(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)>;
+
+    /// Get the value of a key and watch it in the transaction,
+    /// so the transaction only commits if the key is unchanged by then.
+    fn get_watched(&self, txn: &mut Transaction, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let (value, version) = self.get_versioned(key.clone())?;
+        txn.watch(key, version);
+        Ok(value)
+    }
+
+    /// Apply the writes of the transaction atomically if none of its watched keys changed.
+    /// Return an error of kind `Conflict` otherwise and apply nothing.
+    fn commit(&self, txn: Transaction) -> Result<()>;
+
+    /// Apply all operations of the batch atomically, even across a crash.
+    /// If a removed key does not exist, return an error and apply nothing.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Iterate over the pairs whose key lies in the range, in ascending key order.
+    /// Writes made while iterating may or may not be seen.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Scan>;
+
+    /// Iterate over the pairs whose key starts with the prefix, in ascending key order.
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Result<Scan> {
+        let end = match utils::prefix_end(&prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        self.scan((Bound::Included(prefix), end))
+    }
+
     /// Set the value of a string key to a string.
     /// Return an error if the value is not written successfully.
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
 
     /// Get the string value of a string key.
     /// If the key does not exist, return `None`.
-    /// Return an error if the value is not read successfully.
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    /// Return an error if the value is not read successfully or is not valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
 
     /// Remove a given string key.
     /// Return an error if the key does not exit or value is not read successfully.
-    fn remove(&mut self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
 }
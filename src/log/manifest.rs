@@ -0,0 +1,175 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::config::*;
+use crate::error::{Error, ErrorKind, Result};
+
+/// the set of live segments in replay order
+///
+/// every change is written to a temporary file which is then renamed over the old manifest,
+/// so a crash leaves either the old or the new version behind, never a mix of both
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    #[serde(skip)]
+    dir: PathBuf,
+    version: u32,
+    /// file names of the segments without extension, oldest first
+    segments: Vec<String>,
+    /// how many times the store has been opened, versions of keys are unique per epoch
+    epoch: u64,
+}
+
+impl Manifest {
+    /// load the manifest in the given directory, `None` if there isn't one yet
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Option<Self>> {
+        let dir = dir.into();
+        let buf = match fs::read(dir.join(MANIFEST_FILE)) {
+            Ok(buf) => buf,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::from(e)),
+        };
+        let invalid = |e| Error::new(ErrorKind::InvalidManifest, e);
+        let version: u32 = bincode::deserialize(&buf).map_err(invalid)?;
+        if version > FORMAT_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidManifest,
+                format!("unsupported format version {}", version),
+            ));
+        }
+        let mut manifest = bincode::deserialize::<Manifest>(&buf).map_err(invalid)?;
+        manifest.dir = dir;
+        Ok(Some(manifest))
+    }
+
+    /// create and persist a manifest listing the given segments
+    pub fn create(dir: impl Into<PathBuf>, segments: &[PathBuf]) -> Result<Self> {
+        let manifest = Self {
+            dir: dir.into(),
+            version: FORMAT_VERSION,
+            segments: segments.iter().map(|seg| Self::name(seg)).collect(),
+            epoch: 0,
+        };
+        manifest.store()?;
+        Ok(manifest)
+    }
+
+    /// record segments written before the manifest existed, which have format version 0
+    pub fn adopt(dir: impl Into<PathBuf>, segments: &[PathBuf]) -> Result<Self> {
+        let manifest = Self {
+            dir: dir.into(),
+            version: 0,
+            segments: segments.iter().map(|seg| Self::name(seg)).collect(),
+            epoch: 0,
+        };
+        manifest.store()?;
+        Ok(manifest)
+    }
+
+    /// format version of the live segments
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// full paths of the live segments, oldest first
+    pub fn segments(&self) -> Vec<PathBuf> {
+        self.segments
+            .iter()
+            .map(|name| {
+                let mut path = self.dir.join(name);
+                path.set_extension(LOG_FILE_EXT);
+                path
+            })
+            .collect()
+    }
+
+    /// start the next epoch and return it, to be called whenever the store is opened
+    pub fn next_epoch(&mut self) -> Result<u64> {
+        let mut next = self.clone();
+        next.epoch += 1;
+        next.store()?;
+        *self = next;
+        Ok(self.epoch)
+    }
+
+    /// append a newly created segment
+    pub fn push(&mut self, segment: &Path) -> Result<()> {
+        let mut next = self.clone();
+        next.segments.push(Self::name(segment));
+        next.store()?;
+        *self = next;
+        Ok(())
+    }
+
+    /// swap compacted segments for their output, which goes right before `before`
+    pub fn replace(
+        &mut self,
+        inputs: &[PathBuf],
+        outputs: &[PathBuf],
+        before: &Path,
+    ) -> Result<()> {
+        let inputs: HashSet<_> = inputs.iter().map(|seg| Self::name(seg)).collect();
+        let before = Self::name(before);
+        let mut next = self.clone();
+        next.segments.retain(|name| !inputs.contains(name));
+        let pos = next
+            .segments
+            .iter()
+            .position(|name| *name == before)
+            .unwrap_or(next.segments.len());
+        next.segments
+            .splice(pos..pos, outputs.iter().map(|seg| Self::name(seg)));
+        next.store()?;
+        *self = next;
+        Ok(())
+    }
+
+    /// delete segment and hint files not listed in the manifest,
+    /// which are left over by an interrupted compaction or rotation
+    pub fn remove_orphans(&self) -> Result<()> {
+        let live: HashSet<_> = self.segments.iter().map(OsStr::new).collect();
+        for res in fs::read_dir(&self.dir)? {
+            let path = res?.path();
+            let ext = path.extension();
+            let orphan = if path.file_name() == Some(OsStr::new(MANIFEST_TMP_FILE)) {
+                true
+            } else if ext == Some(OsStr::new(LOG_FILE_EXT))
+                || ext == Some(OsStr::new(HINT_FILE_EXT))
+            {
+                !matches!(path.file_stem(), Some(stem) if live.contains(stem))
+            } else {
+                false
+            };
+            if orphan && path.is_file() {
+                warn!("removing orphaned file {:?}", path);
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn store(&self) -> Result<()> {
+        let tmp = self.dir.join(MANIFEST_TMP_FILE);
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&bincode::serialize(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(MANIFEST_FILE))?;
+        // persist the rename itself
+        if let Ok(dir) = fs::File::open(&self.dir) {
+            let _ = dir.sync_all();
+        }
+        Ok(())
+    }
+
+    fn name(segment: &Path) -> String {
+        segment
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+}
@@ -1,37 +1,78 @@
 use std::collections::HashMap;
+use std::convert::TryInto;
 use std::fs;
-use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
 
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
 
 use crate::config::*;
 use crate::error::{Error, ErrorKind, Result};
 
+pub(crate) use manifest::Manifest;
+pub(crate) use upgrade::upgrade;
+
+mod manifest;
 #[cfg(test)]
 mod tests;
+mod upgrade;
+
+/// size of the record header: crc32 of the payload, the payload length and a crc32 of both
+const HEADER_SIZE: usize = 12;
 
 /// a single log entry
+/// on disk every entry is framed as `crc32 | len | header crc32 | payload`, all little endian u32
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) enum Entry {
-    Set(String, String),
-    Rm(String),
+    Set(Vec<u8>, Vec<u8>),
+    Rm(Vec<u8>),
+    /// framed `Set` and `Rm` records back to back, which are valid only as a whole
+    /// they make up the tail of the batch record, so pointers can refer to them directly
+    Batch(Vec<u8>),
+    /// a value which expires at the given unix time in milliseconds
+    SetEx(Vec<u8>, Vec<u8>, u64),
 }
 
-/// full path and file offset of a log entry
+/// result of decoding one record from the front of a buffer
 #[derive(Debug)]
+pub(crate) enum Record {
+    /// a valid entry and the number of bytes it occupies
+    Valid(Entry, u64),
+    /// the buffer is empty, i.e. we stopped at a record boundary
+    End,
+    /// the record is the last one in the buffer but is not complete or valid,
+    /// which is what an interrupted append looks like
+    Torn,
+    /// a record followed by more data failed its checksum,
+    /// or its header is damaged so where it ends is unknown
+    Corrupted,
+}
+
+/// full path, file offset and on disk length of a log entry
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) struct Pointer {
     filename: PathBuf,
     offset: u64,
+    len: u64,
 }
 
 /// index for a log file
 /// the on disk hint file contains `offset` and `count` back to back
+/// `offset` maps each key to the offset and length of its latest record
+/// `size` is the length of the log file covered by the hint, a mismatch means the hint is stale
+/// `expires` holds the expiry of keys whose latest record is a `SetEx`
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Hint {
     full_path: PathBuf,
-    offset: HashMap<String, u64>,
-    count: HashMap<String, u64>,
+    offset: HashMap<Vec<u8>, (u64, u64)>,
+    count: HashMap<Vec<u8>, u64>,
+    size: u64,
+    expires: HashMap<Vec<u8>, u64>,
+    /// whether there are changes not yet written back
+    #[serde(skip)]
+    dirty: bool,
 }
 
 /// a log file
@@ -39,7 +80,6 @@ pub(crate) struct Hint {
 pub(crate) struct Segment {
     full_path: PathBuf,
     hint: Hint,
-    reader: BufReader<fs::File>,
     writer: BufWriter<fs::File>,
     write_offset: u64,
 }
@@ -56,17 +96,12 @@ impl Segment {
         format!("{}", chrono::Utc::now().format("%Y-%m-%d-%H-%M-%S-%f"))
     }
 
+    /// open an existing segment for appending
+    /// a torn record at the tail, left by a crash in the middle of a write, is truncated
     pub fn open(file: impl Into<PathBuf>) -> Result<Self> {
         let mut full_path = file.into();
         full_path.set_extension(LOG_FILE_EXT);
-        let hint = match Hint::open(&full_path) {
-            Ok(hint) => hint,
-            Err(e) => match e.kind() {
-                // TODO: rebuild hint file
-                ErrorKind::InvalidHintFile => Hint::new(&full_path),
-                _ => return Err(e),
-            },
-        };
+        let hint = Hint::open(&full_path, true)?;
         // create must be used with write/append
         let mut writer = BufWriter::new(
             fs::File::with_options()
@@ -75,20 +110,25 @@ impl Segment {
                 .open(&full_path)?,
         );
         let write_offset = writer.seek(SeekFrom::End(0))?;
-        let reader = BufReader::new(fs::File::with_options().read(true).open(&full_path)?);
 
         Ok(Self {
             full_path,
             hint,
-            reader,
             writer,
             write_offset,
         })
     }
 
+    /// create a new segment with a generated name in the given directory
     pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
         let mut full_path = dir.into();
         full_path.push(Self::gen_name());
+        Self::create(full_path)
+    }
+
+    /// create a new segment at the given path
+    pub fn create(file: impl Into<PathBuf>) -> Result<Self> {
+        let mut full_path = file.into();
         full_path.set_extension(LOG_FILE_EXT);
         let hint = Hint::new(&full_path);
         // create must be used with write/append
@@ -99,48 +139,99 @@ impl Segment {
                 .open(&full_path)?,
         );
         let write_offset = 0;
-        let reader = BufReader::new(fs::File::with_options().read(true).open(&full_path)?);
 
         Ok(Self {
             full_path,
             hint,
-            reader,
             writer,
             write_offset,
         })
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<Pointer> {
-        let pointer = Pointer::new(&self.full_path, self.write_offset);
-        let entry = Entry::Set(key.clone(), value);
-        let buf = bincode::serialize(&entry)?;
-        self.writer.write_all(&buf)?;
-        self.writer.flush()?;
-        self.write_offset += buf.len() as u64;
-        self.hint.set(key, pointer.offset);
-        // self.hint.flush()?;
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Pointer> {
+        self.set_with_expiry(key, value, None)
+    }
+
+    /// append a value which expires at the given unix time in milliseconds, if any
+    pub fn set_with_expiry(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires: Option<u64>,
+    ) -> Result<Pointer> {
+        let entry = match expires {
+            Some(expires) => Entry::SetEx(key, value, expires),
+            None => Entry::Set(key, value),
+        };
+        let pointer = self.append(&entry)?;
+        self.hint.replay(&entry, pointer.offset, pointer.len)?;
         Ok(pointer)
     }
 
-    pub fn remove(&mut self, key: &str) -> Result<()> {
-        // let pointer = Pointer::new(&self.full_path, self.write_offset);
+    /// append a tombstone of the key, return where the tombstone is written
+    pub fn remove(&mut self, key: &[u8]) -> Result<Pointer> {
         let entry = Entry::Rm(key.into());
-        let buf = bincode::serialize(&entry)?;
+        let pointer = self.append(&entry)?;
+        self.hint.remove(key);
+
+        Ok(pointer)
+    }
+
+    /// append the entries as one batch record, which is recovered either entirely or not at all
+    /// return where each of the entries is written
+    pub fn batch(&mut self, entries: &[Entry]) -> Result<Vec<Pointer>> {
+        let mut inner = Vec::new();
+        let mut spans = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let buf = entry.encode()?;
+            spans.push((inner.len() as u64, buf.len() as u64));
+            inner.extend_from_slice(&buf);
+        }
+        let inner_len = inner.len() as u64;
+        let record = self.append(&Entry::Batch(inner))?;
+        let base = record.offset + record.len - inner_len;
+
+        let mut pointers = Vec::with_capacity(entries.len());
+        for (entry, (pos, len)) in entries.iter().zip(spans) {
+            let pointer = Pointer::new(&self.full_path, base + pos, len);
+            self.hint.replay(entry, pointer.offset, pointer.len)?;
+            pointers.push(pointer);
+        }
+        Ok(pointers)
+    }
+
+    fn append(&mut self, entry: &Entry) -> Result<Pointer> {
+        let buf = entry.encode()?;
         self.writer.write_all(&buf)?;
         self.writer.flush()?;
+        let pointer = Pointer::new(&self.full_path, self.write_offset, buf.len() as u64);
         self.write_offset += buf.len() as u64;
-        self.hint.remove(key);
+        self.hint.size = self.write_offset;
+        Ok(pointer)
+    }
 
+    /// write out buffered records and wait until the log file is on disk
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
         Ok(())
     }
 
+    /// sync the log and write back the hint, so reopening does not have to replay the log
+    pub fn checkpoint(&mut self) -> Result<()> {
+        self.sync()?;
+        self.hint.flush()
+    }
+
+    /// the store reads through pointers, this is for poking at a single segment
+    #[cfg(test)]
     pub fn get(&mut self, key: &str) -> Result<Option<String>> {
-        if let Some(offset) = self.hint.get(key) {
+        if let Some(&(offset, len)) = self.hint.offset.get(key.as_bytes()) {
             self.writer.flush()?;
-            self.reader.seek(SeekFrom::Start(offset))?;
-            let value: Entry = bincode::deserialize_from(&mut self.reader)?;
-            if let Entry::Set(_, v) = value {
-                Ok(Some(v))
+            let file = fs::File::open(&self.full_path)?;
+            let value = Pointer::new(&self.full_path, offset, len).read(&file)?;
+            if let Entry::Set(_, v) | Entry::SetEx(_, v, _) = value {
+                Ok(Some(String::from_utf8(v)?))
             } else {
                 Err(Error::from(ErrorKind::InvalidLogEntry))
             }
@@ -153,64 +244,108 @@ impl Segment {
         self.write_offset
     }
 
-    pub fn flush(&self) -> Result<()> {
-        self.hint.flush()
+    /// seal the segment once it is full
+    /// the hint is written back and the log file is made read-only
+    pub fn seal(mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        self.hint.flush()?;
+        let mut perm = fs::metadata(&self.full_path)?.permissions();
+        perm.set_readonly(true);
+        fs::set_permissions(&self.full_path, perm)?;
+        Ok(())
+    }
+
+    /// check whether the given log file has been sealed
+    pub fn is_sealed(file: &PathBuf) -> Result<bool> {
+        Ok(fs::metadata(file)?.permissions().readonly())
+    }
+
+    /// delete the log file of a segment which is no longer live, along with its hint
+    pub fn delete(file: &Path) -> Result<()> {
+        fs::remove_file(file.with_extension(LOG_FILE_EXT))?;
+        let hint = file.with_extension(HINT_FILE_EXT);
+        if hint.exists() {
+            fs::remove_file(hint)?;
+        }
+        Ok(())
     }
 }
 
 impl Hint {
-    // TODO: ignore hint file when reading or deserialization fails
-    pub fn open(file: impl Into<PathBuf>) -> Result<Self> {
+    /// load the hint of a log file, rebuilding it from the log when it is missing or stale
+    /// `tail` tells whether the log may end with a torn record which should be truncated,
+    /// otherwise an invalid record is reported as corruption
+    pub fn open(file: impl Into<PathBuf>, tail: bool) -> Result<Self> {
         let mut full_path = file.into();
         full_path.set_extension(LOG_FILE_EXT);
         if full_path.exists() {
-            full_path.set_extension(HINT_FILE_EXT);
-            let hint = Hint::load(full_path)?;
-            Ok(hint)
+            Hint::load(full_path, tail)
         } else {
             let hint = Hint::new(full_path);
             Ok(hint)
         }
     }
 
-    fn load(file: impl Into<PathBuf>) -> Result<Self> {
+    fn load(file: impl Into<PathBuf>, tail: bool) -> Result<Self> {
         let mut full_path = file.into();
+        full_path.set_extension(LOG_FILE_EXT);
+        let size = fs::metadata(&full_path)?.len();
         full_path.set_extension(HINT_FILE_EXT);
-        if full_path.exists() {
-            let file = fs::File::with_options()
-                .read(true)
-                .open(full_path.clone())
-                .map_err(|_| Error::from(ErrorKind::InvalidHintFile))?;
-            // TODO: handle deserialization failure by rebuilding hint file
-            let mut hint: Hint = bincode::deserialize_from(file)
-                .map_err(|_| Error::from(ErrorKind::InvalidHintFile))?;
-            hint.full_path = full_path;
-            Ok(hint)
-        } else {
-            Hint::rebuild(full_path)
+        let hint = fs::File::with_options()
+            .read(true)
+            .open(&full_path)
+            .map_err(Error::from)
+            .and_then(|file| bincode::deserialize_from::<_, Hint>(file).map_err(Error::from));
+        match hint {
+            Ok(mut hint) if hint.size == size => {
+                hint.full_path = full_path;
+                Ok(hint)
+            }
+            // either unreadable or the log has been appended since the hint was written
+            _ => Hint::rebuild(full_path, tail),
         }
     }
 
-    fn rebuild(file: impl Into<PathBuf>) -> Result<Self> {
+    fn rebuild(file: impl Into<PathBuf>, tail: bool) -> Result<Self> {
         let mut full_path = file.into();
         full_path.set_extension(HINT_FILE_EXT);
         let mut hint = Hint::new(&full_path);
         full_path.set_extension(LOG_FILE_EXT);
         let mut buf = Vec::new();
-        fs::File::open(full_path)?.read_to_end(&mut buf)?;
-        let mut cursor = Cursor::new(&buf[..]);
-        let mut pos = cursor.seek(SeekFrom::Current(0))?;
-        while let Ok(entry) = bincode::deserialize_from::<_, Entry>(&mut cursor) {
-            match entry {
-                Entry::Set(key, _) => {
-                    hint.set(key, pos);
+        fs::File::open(&full_path)?.read_to_end(&mut buf)?;
+        let mut pos = 0u64;
+        loop {
+            match Entry::decode(&buf[pos as usize..]) {
+                Record::Valid(entry, len) => {
+                    hint.replay(&entry, pos, len)?;
+                    pos += len;
+                }
+                Record::End => break,
+                Record::Torn if tail => {
+                    warn!(
+                        "truncating torn record at {:?} offset {}, {} bytes discarded",
+                        full_path,
+                        pos,
+                        buf.len() as u64 - pos
+                    );
+                    fs::File::with_options()
+                        .write(true)
+                        .open(&full_path)?
+                        .set_len(pos)?;
+                    break;
                 }
-                Entry::Rm(key) => {
-                    hint.remove(&key);
+                Record::Torn | Record::Corrupted => {
+                    error!("corrupted record at {:?} offset {}", full_path, pos);
+                    return Err(Error::new(
+                        ErrorKind::InvalidLogEntry,
+                        format!("corrupted record in {:?} at offset {}", full_path, pos),
+                    ));
                 }
             }
-            pos = cursor.seek(SeekFrom::Current(0))?;
         }
+        hint.size = pos;
+        hint.dirty = true;
         Ok(hint)
     }
 
@@ -222,46 +357,94 @@ impl Hint {
             full_path,
             offset: HashMap::new(),
             count: HashMap::new(),
+            size: 0,
+            expires: HashMap::new(),
+            dirty: false,
         }
     }
 
-    /// change the offset corresponding to given key
-    pub fn set(&mut self, key: String, offset: u64) {
+    /// account the record at the given place of the log
+    fn replay(&mut self, entry: &Entry, offset: u64, len: u64) -> Result<()> {
+        match entry {
+            Entry::Set(key, _) => self.set(key.clone(), offset, len),
+            Entry::SetEx(key, _, expires) => {
+                self.set(key.clone(), offset, len);
+                self.expires.insert(key.clone(), *expires);
+            }
+            Entry::Rm(key) => self.remove(key),
+            Entry::Batch(inner) => {
+                let mut pos = offset + len - inner.len() as u64;
+                let mut buf = &inner[..];
+                while !buf.is_empty() {
+                    match Entry::decode(buf) {
+                        Record::Valid(entry, len) if !matches!(entry, Entry::Batch(_)) => {
+                            self.replay(&entry, pos, len)?;
+                            pos += len;
+                            buf = &buf[len as usize..];
+                        }
+                        _ => {
+                            return Err(Error::new(
+                                ErrorKind::InvalidLogEntry,
+                                format!("invalid entry in batch at offset {}", pos),
+                            ))
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// change the offset and length corresponding to given key
+    pub fn set(&mut self, key: Vec<u8>, offset: u64, len: u64) {
+        self.expires.remove(&key);
         self.offset
             .entry(key.clone())
-            .and_modify(|v| *v = offset)
-            .or_insert(offset);
+            .and_modify(|v| *v = (offset, len))
+            .or_insert((offset, len));
         self.count.entry(key).and_modify(|v| *v += 1).or_insert(1);
-    }
-
-    pub fn get(&self, key: &str) -> Option<u64> {
-        self.offset.get(key).copied()
+        self.dirty = true;
     }
 
     /// remove the given key in hint file
-    pub fn remove(&mut self, key: &str) {
+    pub fn remove(&mut self, key: &[u8]) {
         self.offset.remove(key);
+        self.expires.remove(key);
         self.count
             .entry(key.into())
             .and_modify(|v| *v += 1)
             .or_insert(1);
+        self.dirty = true;
     }
 
     // pub fn path(&self) -> &PathBuf {
     //     &self.full_path
     // }
 
-    pub fn offset(&self) -> &HashMap<String, u64> {
+    pub fn offset(&self) -> &HashMap<Vec<u8>, (u64, u64)> {
         &self.offset
     }
 
-    pub fn count(&self) -> &HashMap<String, u64> {
+    pub fn count(&self) -> &HashMap<Vec<u8>, u64> {
         &self.count
     }
 
+    /// unix time in milliseconds at which keys expire, if they do
+    pub fn expires(&self) -> &HashMap<Vec<u8>, u64> {
+        &self.expires
+    }
+
+    /// length of the log file covered by the hint
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
     /// flush hint file to disk
     /// every flush would override previous result
-    pub fn flush(&self) -> Result<()> {
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
         let _ = fs::File::with_options()
             .create(true)
             .write(true)
@@ -273,6 +456,7 @@ impl Hint {
                     .map(|_| file)
                     .map_err(Error::from)
             })?;
+        self.dirty = false;
         Ok(())
     }
 }
@@ -284,11 +468,86 @@ impl Drop for Hint {
     }
 }
 
+impl Entry {
+    /// serialize the entry into a checksummed record
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let payload = bincode::serialize(self)?;
+        let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+        buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        let header_crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&header_crc.to_le_bytes());
+        buf.extend_from_slice(&payload);
+        Ok(buf)
+    }
+
+    /// decode the record at the front of the buffer
+    pub fn decode(buf: &[u8]) -> Record {
+        if buf.is_empty() {
+            return Record::End;
+        }
+        if buf.len() < HEADER_SIZE {
+            return Record::Torn;
+        }
+        let (crc, len) = match Self::header(&buf[..HEADER_SIZE]) {
+            Some(header) => header,
+            None if buf.len() == HEADER_SIZE => return Record::Torn,
+            // a damaged length must not be taken for the end of the log
+            None => return Record::Corrupted,
+        };
+        // the length is intact, so a record running past the end was cut short
+        let end = HEADER_SIZE + len as usize;
+        if end > buf.len() {
+            return Record::Torn;
+        }
+        let payload = &buf[HEADER_SIZE..end];
+        match bincode::deserialize(payload) {
+            Ok(entry) if crc32fast::hash(payload) == crc => Record::Valid(entry, end as u64),
+            // a bad record at the very end could as well be a partially persisted append
+            _ if end == buf.len() => Record::Torn,
+            _ => Record::Corrupted,
+        }
+    }
+
+    /// read one record from the reader, verifying its checksum
+    pub fn read_from(mut reader: impl Read) -> Result<Self> {
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        let (crc, len) = Self::header(&header).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidLogEntry,
+                "record header checksum mismatch",
+            )
+        })?;
+        let mut payload = Vec::new();
+        reader.take(len as u64).read_to_end(&mut payload)?;
+        if payload.len() != len as usize || crc32fast::hash(&payload) != crc {
+            return Err(Error::new(
+                ErrorKind::InvalidLogEntry,
+                "record checksum mismatch",
+            ));
+        }
+        Ok(bincode::deserialize(&payload)?)
+    }
+
+    /// the payload checksum and length, `None` if the header fails its own checksum
+    fn header(buf: &[u8]) -> Option<(u32, u32)> {
+        let crc = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+        let len = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+        let header_crc = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        if crc32fast::hash(&buf[0..8]) != header_crc {
+            return None;
+        }
+        Some((crc, len))
+    }
+}
+
 impl Pointer {
-    pub fn new(filename: impl Into<PathBuf>, offset: u64) -> Self {
+    pub fn new(filename: impl Into<PathBuf>, offset: u64, len: u64) -> Self {
         Self {
             filename: filename.into(),
             offset,
+            len,
         }
     }
 
@@ -296,7 +555,21 @@ impl Pointer {
         &self.filename
     }
 
-    pub fn offset(&self) -> u64 {
-        self.offset
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// read the entry from the given handle of the log file
+    /// positional reads leave the file cursor alone, so the handle can be shared
+    pub fn read(&self, file: &fs::File) -> Result<Entry> {
+        let mut buf = vec![0u8; self.len as usize];
+        file.read_exact_at(&mut buf, self.offset)?;
+        match Entry::decode(&buf) {
+            Record::Valid(entry, len) if len == self.len => Ok(entry),
+            _ => Err(Error::new(
+                ErrorKind::InvalidLogEntry,
+                "record checksum mismatch",
+            )),
+        }
     }
 }
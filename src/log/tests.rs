@@ -15,16 +15,16 @@ fn segment_sanity_check() -> Result<()> {
     let mut seg = Segment::new(temp_dir.path())?;
     let seg_path = seg.full_path.clone();
 
-    seg.set("key1".to_owned(), "value1".to_owned())?;
-    seg.set("key2".to_owned(), "value2".to_owned())?;
-    seg.set("key3".to_owned(), "value3".to_owned())?;
+    seg.set("key1".into(), "value1".into())?;
+    seg.set("key2".into(), "value2".into())?;
+    seg.set("key3".into(), "value3".into())?;
 
     assert_eq!(seg.get("key1")?.unwrap(), "value1");
     assert_eq!(seg.get("key2")?.unwrap(), "value2");
     assert_eq!(seg.get("key3")?.unwrap(), "value3");
     assert_eq!(seg.get("key4")?, None);
 
-    seg.remove("key2")?;
+    seg.remove(b"key2")?;
     assert_eq!(seg.get("key1")?.unwrap(), "value1");
     assert_eq!(seg.get("key2")?, None);
     assert_eq!(seg.get("key3")?.unwrap(), "value3");
@@ -48,8 +48,8 @@ fn segment_get_stored_value() -> Result<()> {
     let mut seg = Segment::new(temp_dir.path())?;
     let seg_path = seg.full_path.clone();
 
-    seg.set("key1".to_owned(), "value1".to_owned())?;
-    seg.set("key2".to_owned(), "value2".to_owned())?;
+    seg.set("key1".into(), "value1".into())?;
+    seg.set("key2".into(), "value2".into())?;
 
     assert_eq!(seg.get("key1")?, Some("value1".to_owned()));
     assert_eq!(seg.get("key2")?, Some("value2".to_owned()));
@@ -70,15 +70,21 @@ fn segment_overwrite_value() -> Result<()> {
     let mut seg = Segment::new(temp_dir.path())?;
     let seg_path = seg.full_path.clone();
 
-    seg.set("key1".to_owned(), "value1".to_owned())?;
+    seg.set("key1".into(), "value1".into())?;
     assert_eq!(seg.get("key1")?, Some("value1".to_owned()));
-    seg.set("key1".to_owned(), "value2".to_owned())?;
+    seg.set("key1".into(), "value2".into())?;
     assert_eq!(seg.get("key1")?, Some("value2".to_owned()));
     for i in 0..10_000 {
-        seg.set(format!("k{}k", i), format!("V{}V", i))?;
+        seg.set(
+            format!("k{}k", i).into_bytes(),
+            format!("V{}V", i).into_bytes(),
+        )?;
     }
     for i in 0..5_000 {
-        seg.set(format!("k{}k", i), format!("A{}A", i))?;
+        seg.set(
+            format!("k{}k", i).into_bytes(),
+            format!("A{}A", i).into_bytes(),
+        )?;
     }
     for i in 0..5_000 {
         assert_eq!(seg.get(&format!("k{}k", i))?, Some(format!("A{}A", i)));
@@ -91,7 +97,7 @@ fn segment_overwrite_value() -> Result<()> {
     drop(seg);
     let mut seg = Segment::open(seg_path)?;
     assert_eq!(seg.get("key1")?, Some("value2".to_owned()));
-    seg.set("key1".to_owned(), "value3".to_owned())?;
+    seg.set("key1".into(), "value3".into())?;
     assert_eq!(seg.get("key1")?, Some("value3".to_owned()));
     for i in 0..5_000 {
         assert_eq!(seg.get(&format!("k{}k", i))?, Some(format!("A{}A", i)));
@@ -110,7 +116,7 @@ fn segment_get_non_existent_value() -> Result<()> {
     let mut seg = Segment::new(temp_dir.path())?;
     let seg_path = seg.full_path.clone();
 
-    seg.set("key1".to_owned(), "value1".to_owned())?;
+    seg.set("key1".into(), "value1".into())?;
     assert_eq!(seg.get("key2")?, None);
 
     // Open from disk again and check persistent data.
@@ -125,8 +131,293 @@ fn segment_get_non_existent_value() -> Result<()> {
 fn segment_remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut seg = Segment::new(temp_dir.path())?;
-    seg.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(seg.remove("key1").is_ok());
+    seg.set("key1".into(), "value1".into())?;
+    assert!(seg.remove(b"key1").is_ok());
     assert_eq!(seg.get("key1")?, None);
     Ok(())
 }
+
+#[test]
+fn record_round_trip() -> Result<()> {
+    let buf = Entry::Set("key1".into(), "value1".into()).encode()?;
+    match Entry::decode(&buf) {
+        Record::Valid(Entry::Set(k, v), len) => {
+            assert_eq!(k, b"key1");
+            assert_eq!(v, b"value1");
+            assert_eq!(len, buf.len() as u64);
+        }
+        r => panic!("unexpected record {:?}", r),
+    }
+    assert!(matches!(Entry::decode(&[]), Record::End));
+    assert!(matches!(Entry::decode(&buf[..5]), Record::Torn));
+    assert!(matches!(Entry::decode(&buf[..buf.len() - 1]), Record::Torn));
+    if let Entry::Set(_, v) = Entry::read_from(&buf[..])? {
+        assert_eq!(v, b"value1");
+    } else {
+        panic!("expect a set entry");
+    }
+    Ok(())
+}
+
+// A partially written record at the end of the log should be dropped on open.
+#[test]
+fn segment_truncate_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut seg = Segment::new(temp_dir.path())?;
+    let mut seg_path = seg.full_path.clone();
+
+    seg.set("key1".into(), "value1".into())?;
+    seg.set("key2".into(), "value2".into())?;
+    let size = seg.size();
+    drop(seg);
+
+    let torn = Entry::Set("key3".into(), "value3".into()).encode()?;
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&seg_path)?
+        .write_all(&torn[..torn.len() / 2])?;
+
+    let mut seg = Segment::open(&seg_path)?;
+    assert_eq!(seg.size(), size);
+    assert_eq!(fs::metadata(&seg_path)?.len(), size);
+    assert_eq!(seg.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(seg.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(seg.get("key3")?, None);
+
+    seg.set("key3".into(), "value3".into())?;
+    drop(seg);
+    seg_path.set_extension(HINT_FILE_EXT);
+    fs::remove_file(&seg_path)?;
+    let mut seg = Segment::open(&seg_path)?;
+    assert_eq!(seg.get("key3")?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// The entries of a batch are readable on their own, but recovered only all together.
+#[test]
+fn segment_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut seg = Segment::new(temp_dir.path())?;
+    let mut seg_path = seg.full_path.clone();
+
+    seg.set("key1".into(), "value1".into())?;
+    let entries = [
+        Entry::Set("key2".into(), "value2".into()),
+        Entry::Rm("key1".into()),
+        Entry::Set("key3".into(), "value3".into()),
+    ];
+    let pointers = seg.batch(&entries)?;
+    assert_eq!(pointers.len(), 3);
+    assert_eq!(seg.get("key1")?, None);
+    assert_eq!(seg.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(seg.get("key3")?, Some("value3".to_owned()));
+    let size = seg.size();
+
+    let entries = [
+        Entry::Set("key4".into(), "value4".into()),
+        Entry::Set("key5".into(), "value5".into()),
+    ];
+    seg.batch(&entries)?;
+    let torn = size + (seg.size() - size) / 2;
+    drop(seg);
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&seg_path)?
+        .set_len(torn)?;
+    seg_path.set_extension(HINT_FILE_EXT);
+    fs::remove_file(&seg_path)?;
+
+    let mut seg = Segment::open(&seg_path)?;
+    assert_eq!(seg.size(), size);
+    assert_eq!(seg.get("key1")?, None);
+    assert_eq!(seg.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(seg.get("key3")?, Some("value3".to_owned()));
+    assert_eq!(seg.get("key4")?, None);
+    assert_eq!(seg.get("key5")?, None);
+    assert_eq!(seg.hint().count().len(), 3);
+
+    Ok(())
+}
+
+// Corruption in the middle of a log is reported instead of being skipped.
+#[test]
+fn segment_detect_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut seg = Segment::new(temp_dir.path())?;
+    let mut seg_path = seg.full_path.clone();
+
+    seg.set("key1".into(), "value1".into())?;
+    seg.set("key2".into(), "value2".into())?;
+    drop(seg);
+
+    let mut buf = fs::read(&seg_path)?;
+    buf[HEADER_SIZE] ^= 0xff;
+    fs::write(&seg_path, &buf)?;
+    seg_path.set_extension(HINT_FILE_EXT);
+    fs::remove_file(&seg_path)?;
+
+    for &tail in &[false, true] {
+        match Hint::open(&seg_path, tail) {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::InvalidLogEntry)),
+            Ok(_) => panic!("corruption not detected"),
+        }
+    }
+
+    Ok(())
+}
+
+// A hint written before later appends must not be trusted.
+#[test]
+fn segment_stale_hint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut seg = Segment::new(temp_dir.path())?;
+    let seg_path = seg.full_path.clone();
+    seg.set("key1".into(), "value1".into())?;
+    drop(seg);
+
+    // crash before the hint is written back
+    let mut seg = Segment::open(&seg_path)?;
+    seg.set("key2".into(), "value2".into())?;
+    std::mem::forget(seg);
+
+    let mut seg = Segment::open(&seg_path)?;
+    assert_eq!(seg.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(seg.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn segment_seal() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut seg = Segment::new(temp_dir.path())?;
+    let mut seg_path = seg.full_path.clone();
+    seg.set("key1".into(), "value1".into())?;
+    assert!(!Segment::is_sealed(&seg_path)?);
+    seg.seal()?;
+
+    assert!(Segment::is_sealed(&seg_path)?);
+    let hint = Hint::open(&seg_path, false)?;
+    assert_eq!(
+        hint.offset().get(&b"key1"[..]).map(|&(offset, _)| offset),
+        Some(0)
+    );
+    seg_path.set_extension(HINT_FILE_EXT);
+    assert!(seg_path.exists());
+
+    Ok(())
+}
+
+// A checkpoint writes the hint back, which is trusted after a crash if nothing was appended since.
+#[test]
+fn segment_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut seg = Segment::new(temp_dir.path())?;
+    let seg_path = seg.full_path.clone();
+    seg.set("key1".into(), "value1".into())?;
+    seg.remove(b"key1")?;
+    seg.set("key2".into(), "value2".into())?;
+    seg.checkpoint()?;
+    assert!(seg_path.with_extension(HINT_FILE_EXT).exists());
+    std::mem::forget(seg);
+
+    let hint = Hint::open(&seg_path, true)?;
+    assert!(!hint.dirty);
+    assert!(hint.offset().get(&b"key1"[..]).is_none());
+    assert_eq!(hint.count().get(&b"key1"[..]), Some(&2));
+    drop(hint);
+    let mut seg = Segment::open(&seg_path)?;
+    assert_eq!(seg.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A damaged length in the middle of the log must not pass for a torn tail and be truncated.
+#[test]
+fn segment_damaged_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut seg = Segment::new(temp_dir.path())?;
+    let mut seg_path = seg.full_path.clone();
+
+    seg.set("key1".into(), "value1".into())?;
+    seg.set("key2".into(), "value2".into())?;
+    drop(seg);
+
+    let mut buf = fs::read(&seg_path)?;
+    buf[6] = 0xff;
+    fs::write(&seg_path, &buf)?;
+    assert!(matches!(Entry::decode(&buf), Record::Corrupted));
+    let log_path = seg_path.clone();
+    seg_path.set_extension(HINT_FILE_EXT);
+    fs::remove_file(&seg_path)?;
+
+    assert!(Segment::open(&log_path).is_err());
+    assert_eq!(fs::metadata(&log_path)?.len(), buf.len() as u64);
+
+    Ok(())
+}
+
+fn read_converted(manifest: &Manifest) -> Result<HashMap<Vec<u8>, Option<Vec<u8>>>> {
+    let mut values = HashMap::new();
+    for path in manifest.segments() {
+        assert!(Segment::is_sealed(&path)?);
+        let hint = Hint::open(&path, false)?;
+        let file = fs::File::open(&path)?;
+        for key in hint.count().keys() {
+            let value = match hint.offset().get(key) {
+                Some(&(offset, len)) => match Pointer::new(&path, offset, len).read(&file)? {
+                    Entry::Set(_, v) | Entry::SetEx(_, v, _) => Some(v),
+                    _ => panic!("expect a set entry"),
+                },
+                None => None,
+            };
+            values.insert(key.clone(), value);
+        }
+    }
+    Ok(values)
+}
+
+// Directories from before the manifest hold bare entries, which are rewritten on open.
+#[test]
+fn upgrade_unframed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old = temp_dir.path().join("2020-01-01-00-00-00-000000000.kvs");
+    let mut buf = Vec::new();
+    for entry in &[
+        Entry::Set("key1".into(), "value1".into()),
+        Entry::Set("key2".into(), "value2".into()),
+        Entry::Rm("key1".into()),
+    ] {
+        buf.extend(bincode::serialize(entry).unwrap());
+    }
+    // cut short by a crash
+    let torn = bincode::serialize(&Entry::Set("key3".into(), "value3".into())).unwrap();
+    buf.extend_from_slice(&torn[..torn.len() - 2]);
+    fs::write(&old, &buf)?;
+
+    let manifest = upgrade(temp_dir.path())?;
+    assert_eq!(manifest.version(), FORMAT_VERSION);
+    assert!(!old.exists());
+    let values = read_converted(&manifest)?;
+    assert_eq!(values.get(&b"key1"[..]), Some(&None));
+    assert_eq!(values.get(&b"key2"[..]), Some(&Some(b"value2".to_vec())));
+    assert_eq!(values.get(&b"key3"[..]), None);
+
+    // opening again finds the current format and leaves it alone
+    let again = upgrade(temp_dir.path())?;
+    assert_eq!(again.segments(), manifest.segments());
+
+    // unreadable data is refused instead of being taken for the end of the log
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old = temp_dir.path().join("2020-01-01-00-00-00-000000000.kvs");
+    fs::write(&old, b"not a log entry at all")?;
+    fs::write(old.with_file_name("2020-01-02-00-00-00-000000000.kvs"), b"")?;
+    match upgrade(temp_dir.path()) {
+        Err(e) => assert!(matches!(e.kind(), ErrorKind::InvalidLogEntry)),
+        Ok(_) => panic!("garbage converted"),
+    }
+    assert_eq!(fs::read(&old)?, b"not a log entry at all");
+
+    Ok(())
+}
@@ -0,0 +1,131 @@
+//! conversion of data directories written by older versions of kvs
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use super::{Entry, Manifest, Record, Segment};
+use crate::config::*;
+use crate::error::{Error, ErrorKind, Result};
+
+/// load the manifest of the directory, converting data written by older versions first
+///
+/// Segments written before the manifest existed are rewritten in the current format and
+/// swapped in by the manifest, an interrupted conversion leaves the old ones live and
+/// starts over on the next open.
+pub(crate) fn upgrade(dir: &Path) -> Result<Manifest> {
+    let manifest = match Manifest::open(dir)? {
+        Some(manifest) => manifest,
+        None => {
+            // log files are created by time order, which should be in ascending order
+            let segments = list_segments(dir)?;
+            if is_current(&segments)? {
+                // either a new directory or one which lost its manifest
+                return Manifest::create(dir, &segments);
+            }
+            Manifest::adopt(dir, &segments)?
+        }
+    };
+    if manifest.version() == FORMAT_VERSION {
+        return Ok(manifest);
+    }
+    warn!(
+        "converting {:?} from format version {} to {}",
+        dir,
+        manifest.version(),
+        FORMAT_VERSION
+    );
+    // output of an earlier attempt
+    manifest.remove_orphans()?;
+    let inputs = manifest.segments();
+    let mut outputs = Vec::with_capacity(inputs.len());
+    for (i, input) in inputs.iter().enumerate() {
+        // only the newest segment could have been written to when we crashed
+        let tail = i + 1 == inputs.len();
+        let mut output = Segment::new(dir)?;
+        for entry in unframed(input, &fs::read(input)?, tail)? {
+            match entry {
+                Entry::Set(key, value) => {
+                    output.set(key, value)?;
+                }
+                Entry::Rm(key) => {
+                    output.remove(&key)?;
+                }
+                _ => unreachable!("bare entries are sets and removals"),
+            }
+        }
+        outputs.push(output.path().clone());
+        output.seal()?;
+    }
+    let manifest = Manifest::create(dir, &outputs)?;
+    for input in &inputs {
+        Segment::delete(input)?;
+    }
+    Ok(manifest)
+}
+
+fn list_segments(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut segments = Vec::new();
+    for res in fs::read_dir(dir)? {
+        let path = res?.path();
+        if path.is_file() && path.extension() == Some(OsStr::new(LOG_FILE_EXT)) {
+            segments.push(path);
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// whether the first record found in the segments is in the current format,
+/// bare entries written before the manifest existed fail the header checksum
+fn is_current(segments: &[PathBuf]) -> Result<bool> {
+    for segment in segments {
+        let buf = fs::read(segment)?;
+        match Entry::decode(&buf) {
+            Record::End => continue,
+            Record::Valid(..) => return Ok(true),
+            Record::Torn | Record::Corrupted => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+/// bare entries back to back, as written before the manifest existed
+/// they only ever held sets and removals
+fn unframed(path: &Path, buf: &[u8], tail: bool) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut reader = buf;
+    while !reader.is_empty() {
+        let pos = buf.len() - reader.len();
+        match bincode::deserialize_from(&mut reader) {
+            Ok(entry @ Entry::Set(..)) | Ok(entry @ Entry::Rm(_)) => entries.push(entry),
+            Err(ref e) if tail && cut_short(e) => {
+                warn!("dropping torn record at {:?} offset {}", path, pos);
+                break;
+            }
+            _ => return Err(unreadable(path, pos)),
+        }
+    }
+    Ok(entries)
+}
+
+/// whether the input ended in the middle of an entry
+fn cut_short(e: &bincode::Error) -> bool {
+    match **e {
+        bincode::ErrorKind::Io(ref e) => e.kind() == io::ErrorKind::UnexpectedEof,
+        _ => false,
+    }
+}
+
+fn unreadable(path: &Path, pos: usize) -> Error {
+    Error::new(
+        ErrorKind::InvalidLogEntry,
+        format!(
+            "cannot convert {:?} to format version {}, corrupted record at offset {}",
+            path, FORMAT_VERSION, pos
+        ),
+    )
+}
@@ -0,0 +1,227 @@
+use std::vec;
+
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+
+use super::Resp;
+use crate::{Error, Result};
+
+/// a sequence of values, from an array, set or push
+struct SeqDeserializer {
+    iter: vec::IntoIter<Resp>,
+}
+
+/// key value pairs, from a map
+struct MapDeserializer {
+    iter: vec::IntoIter<(Resp, Resp)>,
+    value: Option<Resp>,
+}
+
+/// an enum variant, either given by its name alone or as a map from the name to its data
+struct EnumDeserializer {
+    variant: Resp,
+    value: Option<Resp>,
+}
+
+/// the data of an enum variant
+struct VariantDeserializer {
+    value: Option<Resp>,
+}
+
+fn custom(msg: String) -> Error {
+    <Error as de::Error>::custom(msg)
+}
+
+impl<'de> de::Deserializer<'de> for Resp {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self {
+            Resp::NullBulk | Resp::NullArray | Resp::Null => visitor.visit_unit(),
+            Resp::Simple(s) => visitor.visit_string(s),
+            Resp::Error(e) => Err(custom(format!("error reply: {}", e))),
+            Resp::Integer(i) => visitor.visit_i64(i),
+            Resp::Double(d) => visitor.visit_f64(d),
+            Resp::Boolean(b) => visitor.visit_bool(b),
+            Resp::BigNumber(n) => {
+                if let Ok(u) = n.parse() {
+                    visitor.visit_u64(u)
+                } else if let Ok(i) = n.parse() {
+                    visitor.visit_i128(i)
+                } else if let Ok(u) = n.parse() {
+                    visitor.visit_u128(u)
+                } else {
+                    visitor.visit_string(n)
+                }
+            }
+            Resp::Bulk(b) | Resp::Verbatim(_, b) => match String::from_utf8(b) {
+                Ok(s) => visitor.visit_string(s),
+                Err(e) => visitor.visit_byte_buf(e.into_bytes()),
+            },
+            Resp::Array(arr) | Resp::Set(arr) | Resp::Push(arr) => {
+                let len = arr.len();
+                let mut seq = SeqDeserializer {
+                    iter: arr.into_iter(),
+                };
+                let val = visitor.visit_seq(&mut seq)?;
+                match seq.iter.len() {
+                    0 => Ok(val),
+                    _ => Err(de::Error::invalid_length(len, &"fewer elements")),
+                }
+            }
+            Resp::Map(pairs) => visitor.visit_map(MapDeserializer {
+                iter: pairs.into_iter(),
+                value: None,
+            }),
+            // attributes are auxiliary, the value is what matters
+            Resp::Attribute(_, val) => val.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.is_null() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self {
+            Resp::Bulk(b) | Resp::Verbatim(_, b) => visitor.visit_byte_buf(b),
+            val => val.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        let (variant, value) = match self {
+            Resp::Map(pairs) if pairs.len() == 1 => {
+                let (variant, value) = pairs.into_iter().next().unwrap();
+                (variant, Some(value))
+            }
+            Resp::Map(_) => return Err(custom("expected a map with a single key".to_owned())),
+            variant => (variant, None),
+        };
+        visitor.visit_enum(EnumDeserializer { variant, value })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for Resp {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for SeqDeserializer {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        match self.iter.next() {
+            Some(val) => seed.deserialize(val).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+impl<'de> de::MapAccess<'de> for MapDeserializer {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(key).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(value),
+            None => Err(custom("map value without a key".to_owned())),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for EnumDeserializer {
+    type Error = Error;
+    type Variant = VariantDeserializer;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, VariantDeserializer)> {
+        let variant = seed.deserialize(self.variant)?;
+        Ok((variant, VariantDeserializer { value: self.value }))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for VariantDeserializer {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        match self.value {
+            None => Ok(()),
+            Some(val) if val.is_null() => Ok(()),
+            Some(_) => Err(custom("expected a unit variant".to_owned())),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        match self.value {
+            Some(val) => seed.deserialize(val),
+            None => Err(custom("expected a newtype variant".to_owned())),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        match self.value {
+            Some(val) => de::Deserializer::deserialize_seq(val, visitor),
+            None => Err(custom("expected a tuple variant".to_owned())),
+        }
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.value {
+            Some(val) => de::Deserializer::deserialize_map(val, visitor),
+            None => Err(custom("expected a struct variant".to_owned())),
+        }
+    }
+}
@@ -0,0 +1,856 @@
+//! The redis serialization protocol
+//!
+//! Besides the `Resp` value itself, rust types implementing serde's traits can be converted
+//! to and from RESP directly with `to_vec`, `from_slice`, `to_writer` and `from_reader`.
+
+use std::io::{BufRead, Read, Write};
+use std::str;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{Error, ErrorKind, Result};
+
+mod de;
+mod ser;
+
+/// convert a value into a `Resp` tree
+///
+/// Collections use RESP3 types, `Resp::into_resp2` converts the result for RESP2 peers.
+pub fn to_resp<T: ?Sized + Serialize>(value: &T) -> Result<Resp> {
+    value.serialize(ser::Serializer)
+}
+
+/// convert a `Resp` tree into a value, an error reply fails the conversion
+pub fn from_resp<T: DeserializeOwned>(resp: Resp) -> Result<T> {
+    T::deserialize(resp)
+}
+
+/// serialize a value into RESP bytes
+pub fn to_vec<T: ?Sized + Serialize>(value: &T) -> Result<Vec<u8>> {
+    to_resp(value)?.ser()
+}
+
+/// serialize a value as RESP into the writer
+pub fn to_writer<T: ?Sized + Serialize>(mut writer: impl Write, value: &T) -> Result<()> {
+    writer.write_all(&to_vec(value)?)?;
+    Ok(())
+}
+
+/// deserialize a value from RESP bytes
+pub fn from_slice<T: DeserializeOwned>(buf: &[u8]) -> Result<T> {
+    from_resp(Resp::de(buf)?)
+}
+
+/// deserialize one value read from the reader, bytes following it are left in the reader
+pub fn from_reader<T: DeserializeOwned>(mut reader: impl BufRead) -> Result<T> {
+    from_resp(Resp::read_from(&mut reader)?)
+}
+
+/// bulk strings longer than this are rejected instead of allocated, same limit as redis
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+
+/// lines without CRLF longer than this are rejected instead of buffered
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// aggregates nested deeper than this are rejected, a peer cannot make the decoder grow without bound
+const MAX_DEPTH: usize = 128;
+
+/// RESP protocol specifications
+#[derive(Debug, PartialEq)]
+pub enum Resp {
+    /// bulk string with length -1
+    NullBulk,
+    /// array with length -1
+    NullArray,
+    /// simple one line string without CR or LF
+    Simple(String),
+    /// string of error message
+    Error(String),
+    /// integer
+    Integer(i64),
+    /// bulk string, may be used to contain binary data safely
+    Bulk(Vec<u8>),
+    /// recursive array
+    Array(Vec<Resp>),
+    /// RESP3 null, replacing the two RESP2 ones
+    Null,
+    /// RESP3 floating point number
+    Double(f64),
+    /// RESP3 boolean
+    Boolean(bool),
+    /// RESP3 integer of arbitrary size, kept as its decimal representation
+    BigNumber(String),
+    /// RESP3 string with a three letter format such as `txt` or `mkd`
+    Verbatim(String, Vec<u8>),
+    /// RESP3 ordered key value pairs
+    Map(Vec<(Resp, Resp)>),
+    /// RESP3 unordered collection of distinct values
+    Set(Vec<Resp>),
+    /// RESP3 auxiliary key value pairs annotating the value that follows them
+    Attribute(Vec<(Resp, Resp)>, Box<Resp>),
+    /// RESP3 out of band data sent by the server
+    Push(Vec<Resp>),
+}
+
+impl Resp {
+    /// used for filter whether a variant is simple string
+    pub fn is_simple(&self) -> bool {
+        matches!(self, Resp::Simple(_))
+    }
+
+    /// used for filter whether a variant is array
+    pub fn is_array(&self) -> bool {
+        matches!(self, Resp::Array(_))
+    }
+
+    /// check whether the value is one of the special null values
+    pub fn is_null(&self) -> bool {
+        matches!(self, Resp::NullArray | Resp::NullBulk | Resp::Null)
+    }
+
+    /// convert the value into the closest RESP2 equivalent, for clients which did not opt in to RESP3
+    pub fn into_resp2(self) -> Resp {
+        let flatten = |pairs: Vec<(Resp, Resp)>| {
+            pairs
+                .into_iter()
+                .flat_map(|(k, v)| vec![k.into_resp2(), v.into_resp2()])
+                .collect()
+        };
+        match self {
+            Resp::Null => Resp::NullBulk,
+            Resp::Double(d) => Resp::Bulk(Self::format_double(d).into_bytes()),
+            Resp::Boolean(b) => Resp::Integer(b as i64),
+            Resp::BigNumber(n) => Resp::Bulk(n.into_bytes()),
+            Resp::Verbatim(_, data) => Resp::Bulk(data),
+            Resp::Map(pairs) => Resp::Array(flatten(pairs)),
+            Resp::Attribute(_, val) => val.into_resp2(),
+            Resp::Array(arr) | Resp::Set(arr) | Resp::Push(arr) => {
+                Resp::Array(arr.into_iter().map(Resp::into_resp2).collect())
+            }
+            val => val,
+        }
+    }
+
+    /// get the byte representation of the serialized data
+    pub fn ser(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.ser_impl(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// deserialize data from byte buffer
+    pub fn de(buf: &[u8]) -> Result<Self> {
+        let mut decoder = RespDecoder::new();
+        decoder.feed(buf);
+        decoder
+            .decode()?
+            .ok_or_else(|| Error::from(ErrorKind::InvalidResp))
+    }
+
+    /// read one value from the reader, blocking until all of it has arrived
+    /// bytes following the value are left in the reader
+    pub fn read_from(reader: &mut impl BufRead) -> Result<Self> {
+        RespDecoder::new()
+            .read_from(reader)?
+            .ok_or_else(|| Error::from(ErrorKind::InvalidResp))
+    }
+
+    /// read a line terminated by CRLF, which is stripped
+    /// lines longer than `MAX_LINE_LEN` are rejected without reading more of them
+    pub(crate) fn read_line(reader: &mut impl BufRead) -> Result<Vec<u8>> {
+        let mut line = Vec::new();
+        let limit = MAX_LINE_LEN as u64 + 2;
+        reader.by_ref().take(limit).read_until(b'\n', &mut line)?;
+        if line.len() as u64 == limit && !line.ends_with(b"\r\n") {
+            return Err(Error::new(ErrorKind::InvalidResp, "line too long"));
+        }
+        if !line.ends_with(b"\r\n") {
+            // either the peer hung up or the line is not properly terminated
+            return Err(Error::from(ErrorKind::InvalidResp));
+        }
+        line.truncate(line.len() - 2);
+        Ok(line)
+    }
+
+    fn ser_impl(&self, buf: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Resp::NullBulk => buf.write_all(b"$-1\r\n")?,
+            Resp::NullArray => buf.write_all(b"*-1\r\n")?,
+            Resp::Null => buf.write_all(b"_\r\n")?,
+            Resp::Simple(s) => Self::ser_line(buf, b'+', s.as_bytes())?,
+            Resp::Error(s) => Self::ser_line(buf, b'-', s.as_bytes())?,
+            Resp::Integer(i) => Self::ser_line(buf, b':', i.to_string().as_bytes())?,
+            Resp::Double(d) => Self::ser_line(buf, b',', Self::format_double(*d).as_bytes())?,
+            Resp::Boolean(b) => Self::ser_line(buf, b'#', if *b { b"t" } else { b"f" })?,
+            Resp::BigNumber(n) => Self::ser_line(buf, b'(', n.as_bytes())?,
+            Resp::Bulk(b) => {
+                Self::ser_line(buf, b'$', b.len().to_string().as_bytes())?;
+                buf.write_all(b)?;
+                buf.write_all(b"\r\n")?;
+            }
+            Resp::Verbatim(format, data) => {
+                if format.len() != 3 {
+                    return Err(Error::from(ErrorKind::InvalidResp));
+                }
+                let len = format.len() + 1 + data.len();
+                Self::ser_line(buf, b'=', len.to_string().as_bytes())?;
+                buf.write_all(format.as_bytes())?;
+                buf.write_all(b":")?;
+                buf.write_all(data)?;
+                buf.write_all(b"\r\n")?;
+            }
+            Resp::Array(arr) => Self::ser_seq(buf, b'*', arr)?,
+            Resp::Set(arr) => Self::ser_seq(buf, b'~', arr)?,
+            Resp::Push(arr) => Self::ser_seq(buf, b'>', arr)?,
+            Resp::Map(pairs) => Self::ser_pairs(buf, b'%', pairs)?,
+            Resp::Attribute(pairs, val) => {
+                Self::ser_pairs(buf, b'|', pairs)?;
+                val.ser_impl(buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn ser_line(buf: &mut Vec<u8>, prefix: u8, line: &[u8]) -> Result<()> {
+        buf.write_all(&[prefix])?;
+        buf.write_all(line)?;
+        buf.write_all(b"\r\n")?;
+        Ok(())
+    }
+
+    fn ser_seq(buf: &mut Vec<u8>, prefix: u8, arr: &[Resp]) -> Result<()> {
+        Self::ser_line(buf, prefix, arr.len().to_string().as_bytes())?;
+        for val in arr {
+            val.ser_impl(buf)?;
+        }
+        Ok(())
+    }
+
+    fn ser_pairs(buf: &mut Vec<u8>, prefix: u8, pairs: &[(Resp, Resp)]) -> Result<()> {
+        Self::ser_line(buf, prefix, pairs.len().to_string().as_bytes())?;
+        for (k, v) in pairs {
+            k.ser_impl(buf)?;
+            v.ser_impl(buf)?;
+        }
+        Ok(())
+    }
+
+    fn format_double(d: f64) -> String {
+        if d.is_nan() {
+            "nan".to_owned()
+        } else if d.is_infinite() {
+            if d > 0.0 { "inf" } else { "-inf" }.to_owned()
+        } else {
+            d.to_string()
+        }
+    }
+
+    fn parse_double(s: &str) -> Result<f64> {
+        match s {
+            "inf" => Ok(f64::INFINITY),
+            "-inf" => Ok(f64::NEG_INFINITY),
+            "nan" => Ok(f64::NAN),
+            _ => s.parse().map_err(|e| Error::new(ErrorKind::InvalidResp, e)),
+        }
+    }
+
+    fn pairs(vec: Vec<Self>) -> Vec<(Self, Self)> {
+        let mut pairs = Vec::with_capacity(vec.len() / 2);
+        let mut iter = vec.into_iter();
+        while let (Some(k), Some(v)) = (iter.next(), iter.next()) {
+            pairs.push((k, v));
+        }
+        pairs
+    }
+}
+
+/// an aggregate whose elements are still being decoded
+#[derive(Debug)]
+struct Frame {
+    /// the type byte the aggregate starts with
+    kind: u8,
+    /// elements which did not arrive yet
+    remaining: usize,
+    items: Vec<Resp>,
+}
+
+impl Frame {
+    fn into_resp(self) -> Resp {
+        let mut items = self.items;
+        match self.kind {
+            b'*' => Resp::Array(items),
+            b'~' => Resp::Set(items),
+            b'>' => Resp::Push(items),
+            b'%' => Resp::Map(Resp::pairs(items)),
+            _ => {
+                // the annotated value follows the attributes
+                let val = items.pop().expect("attributes are followed by a value");
+                Resp::Attribute(Resp::pairs(items), Box::new(val))
+            }
+        }
+    }
+}
+
+/// outcome of decoding the element at the current offset
+enum Parsed {
+    /// the buffer ends before the element does
+    Incomplete,
+    /// an aggregate was started and pushed on the stack
+    Started,
+    Value(Resp),
+}
+
+/// incremental RESP decoder
+///
+/// Input may arrive in pieces of any size, e.g. as returned by reads from a socket.
+/// Values are handed out once all of their bytes are there.
+/// Elements already decoded are kept, so every byte is only looked at once.
+#[derive(Debug, Default)]
+pub struct RespDecoder {
+    /// received bytes, those before `start` belong to values already handed out
+    buf: Vec<u8>,
+    start: usize,
+    /// offset up to which bytes were decoded into the aggregates on the stack
+    pos: usize,
+    /// offset up to which the line at `pos` is known to have no CRLF
+    scanned: usize,
+    /// aggregates started but not complete yet, innermost last
+    stack: Vec<Frame>,
+}
+
+impl RespDecoder {
+    /// create a decoder without any pending input
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// append received bytes
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if self.start > 0 {
+            self.buf.drain(..self.start);
+            self.pos -= self.start;
+            self.scanned = self.scanned.saturating_sub(self.start);
+            self.start = 0;
+        }
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// take the next value out of the fed bytes, `None` if more bytes are needed
+    pub fn decode(&mut self) -> Result<Option<Resp>> {
+        loop {
+            let mut val = match self.element()? {
+                Parsed::Incomplete => return Ok(None),
+                Parsed::Started => continue,
+                Parsed::Value(val) => val,
+            };
+            // a complete value may complete the aggregates around it as well
+            loop {
+                let frame = match self.stack.last_mut() {
+                    Some(frame) => frame,
+                    None => {
+                        self.start = self.pos;
+                        return Ok(Some(val));
+                    }
+                };
+                frame.items.push(val);
+                frame.remaining -= 1;
+                if frame.remaining > 0 {
+                    break;
+                }
+                val = self.stack.pop().unwrap().into_resp();
+            }
+        }
+    }
+
+    /// read until the next value is complete, `None` if the reader ends between values
+    ///
+    /// Only the bytes of that value are consumed from the reader,
+    /// whatever follows stays there for the next call.
+    pub fn read_from(&mut self, reader: &mut impl BufRead) -> Result<Option<Resp>> {
+        if let Some(val) = self.decode()? {
+            return Ok(Some(val));
+        }
+        loop {
+            let avail = reader.fill_buf()?;
+            if avail.is_empty() {
+                return if self.start == self.buf.len() {
+                    Ok(None)
+                } else {
+                    // the peer hung up in the middle of a value
+                    Err(Error::from(ErrorKind::InvalidResp))
+                };
+            }
+            let len = avail.len();
+            self.feed(avail);
+            let pending = self.buf.len() - len;
+            match self.decode()? {
+                Some(val) => {
+                    reader.consume(self.start - pending);
+                    self.buf.truncate(self.start);
+                    return Ok(Some(val));
+                }
+                None => reader.consume(len),
+            }
+        }
+    }
+
+    /// decode the element at `pos`, advancing past it unless it is incomplete
+    fn element(&mut self) -> Result<Parsed> {
+        let end = match self.line_end()? {
+            Some(end) => end,
+            None => return Ok(Parsed::Incomplete),
+        };
+        let next = end + 2;
+        let line = &self.buf[self.pos..end];
+        if line.is_empty() {
+            return Err(Error::from(ErrorKind::InvalidResp));
+        }
+        let kind = line[0];
+        let body = str::from_utf8(&line[1..])?;
+        let val = match kind {
+            b'+' => Resp::Simple(body.to_owned()),
+            b'-' => Resp::Error(body.to_owned()),
+            b':' => Resp::Integer(body.parse()?),
+            b'_' if body.is_empty() => Resp::Null,
+            b',' => Resp::Double(Resp::parse_double(body)?),
+            b'#' if body == "t" => Resp::Boolean(true),
+            b'#' if body == "f" => Resp::Boolean(false),
+            b'(' => {
+                let digits = body.strip_prefix('-').unwrap_or(body);
+                if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(Error::from(ErrorKind::InvalidResp));
+                }
+                Resp::BigNumber(body.to_owned())
+            }
+            b'$' if body.starts_with('-') => {
+                body.parse::<i64>()?;
+                Resp::NullBulk
+            }
+            b'$' | b'=' => {
+                let len: i64 = body.parse()?;
+                if !(0..=MAX_BULK_LEN).contains(&len) {
+                    return Err(Error::from(ErrorKind::InvalidResp));
+                }
+                let end = next + len as usize;
+                if self.buf.len() < end + 2 {
+                    return Ok(Parsed::Incomplete);
+                }
+                if &self.buf[end..end + 2] != b"\r\n" {
+                    return Err(Error::from(ErrorKind::InvalidResp));
+                }
+                let data = &self.buf[next..end];
+                let val = if kind == b'$' {
+                    Resp::Bulk(data.to_owned())
+                } else if data.len() >= 4 && data[3] == b':' {
+                    let format = str::from_utf8(&data[..3])?.to_owned();
+                    Resp::Verbatim(format, data[4..].to_owned())
+                } else {
+                    return Err(Error::from(ErrorKind::InvalidResp));
+                };
+                self.pos = end + 2;
+                return Ok(Parsed::Value(val));
+            }
+            b'*' if body.starts_with('-') => {
+                body.parse::<i64>()?;
+                Resp::NullArray
+            }
+            b'*' | b'~' | b'>' | b'%' | b'|' => {
+                let len: usize = body.parse()?;
+                // maps and attributes hold two values per entry,
+                // attributes are followed by the value they annotate
+                let remaining = match kind {
+                    b'%' => len.checked_mul(2),
+                    b'|' => len.checked_mul(2).and_then(|n| n.checked_add(1)),
+                    _ => Some(len),
+                }
+                .ok_or_else(|| Error::from(ErrorKind::InvalidResp))?;
+                self.pos = next;
+                let frame = Frame {
+                    kind,
+                    remaining,
+                    items: Vec::new(),
+                };
+                if remaining == 0 {
+                    return Ok(Parsed::Value(frame.into_resp()));
+                }
+                if self.stack.len() == MAX_DEPTH {
+                    let msg = format!("values nested deeper than {} levels", MAX_DEPTH);
+                    return Err(Error::new(ErrorKind::InvalidResp, msg));
+                }
+                self.stack.push(frame);
+                return Ok(Parsed::Started);
+            }
+            _ => return Err(Error::from(ErrorKind::InvalidResp)),
+        };
+        self.pos = next;
+        Ok(Parsed::Value(val))
+    }
+
+    /// the offset of the CRLF ending the line at `pos`, `None` if it did not arrive yet
+    fn line_end(&mut self) -> Result<Option<usize>> {
+        let from = self.scanned.max(self.pos);
+        match self.buf[from..].windows(2).position(|w| w == b"\r\n") {
+            Some(i) => Ok(Some(from + i)),
+            None if self.buf.len() - self.pos > MAX_LINE_LEN => {
+                Err(Error::from(ErrorKind::InvalidResp))
+            }
+            None => {
+                // the CR may be the last byte, its LF still to come
+                self.scanned = self.buf.len().saturating_sub(1).max(self.pos);
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Command {
+        Ping,
+        Get(String),
+        Set(String, String),
+        Scan { prefix: String, count: Option<u32> },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message {
+        id: u64,
+        ok: bool,
+        ratio: f64,
+        tag: char,
+        name: String,
+        commands: Vec<Command>,
+        stats: BTreeMap<String, i32>,
+        parent: Option<Box<Message>>,
+        unit: (),
+    }
+
+    fn round_trip<T>(val: &T)
+    where
+        T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug,
+    {
+        let buf = to_vec(val).unwrap();
+        assert_eq!(&from_slice::<T>(&buf).unwrap(), val);
+        let mut out = Vec::new();
+        to_writer(&mut out, val).unwrap();
+        assert_eq!(out, buf);
+        assert_eq!(&from_reader::<T>(&buf[..]).unwrap(), val);
+    }
+
+    #[test]
+    fn serde_primitives() {
+        round_trip(&true);
+        round_trip(&-42i8);
+        round_trip(&u64::MAX);
+        round_trip(&i128::MIN);
+        round_trip(&1.25f32);
+        round_trip(&'x');
+        round_trip(&"foo bar".to_owned());
+        round_trip(&Some(3u16));
+        round_trip(&None::<String>);
+        round_trip(&(1u8, "two".to_owned(), 3.0f64));
+        assert_eq!(
+            to_resp(&u64::MAX).unwrap(),
+            Resp::BigNumber(u64::MAX.to_string())
+        );
+        assert_eq!(to_resp("key").unwrap(), Resp::Bulk(b"key".to_vec()));
+    }
+
+    #[test]
+    fn serde_enums() {
+        round_trip(&Command::Ping);
+        round_trip(&Command::Get("key".to_owned()));
+        round_trip(&Command::Set("key".to_owned(), "value".to_owned()));
+        round_trip(&Command::Scan {
+            prefix: "tenant:".to_owned(),
+            count: None,
+        });
+        assert_eq!(
+            to_resp(&Command::Ping).unwrap(),
+            Resp::Bulk(b"Ping".to_vec())
+        );
+        assert_eq!(
+            to_resp(&Command::Get("key".to_owned())).unwrap(),
+            Resp::Map(vec![(
+                Resp::Bulk(b"Get".to_vec()),
+                Resp::Bulk(b"key".to_vec())
+            )])
+        );
+    }
+
+    #[test]
+    fn serde_structs() {
+        let mut stats = BTreeMap::new();
+        stats.insert("hits".to_owned(), 10);
+        stats.insert("misses".to_owned(), -1);
+        let parent = Message {
+            id: 1,
+            ok: false,
+            ratio: 0.5,
+            tag: 'p',
+            name: "parent".to_owned(),
+            commands: Vec::new(),
+            stats: BTreeMap::new(),
+            parent: None,
+            unit: (),
+        };
+        round_trip(&Message {
+            id: 2,
+            ok: true,
+            ratio: f64::INFINITY,
+            tag: 'c',
+            name: "child".to_owned(),
+            commands: vec![Command::Ping, Command::Get("key".to_owned())],
+            stats,
+            parent: Some(Box::new(parent)),
+            unit: (),
+        });
+    }
+
+    #[test]
+    fn serde_errors() {
+        assert!(from_slice::<u8>(b":300\r\n").is_err());
+        assert!(from_slice::<String>(b"-ERR boom\r\n").is_err());
+        assert!(from_slice::<(u8, u8)>(b"*3\r\n:1\r\n:2\r\n:3\r\n").is_err());
+        // RESP2 null and plain values map just as well
+        assert_eq!(from_slice::<Option<u8>>(b"$-1\r\n").unwrap(), None);
+        assert_eq!(from_slice::<String>(b"+OK\r\n").unwrap(), "OK");
+    }
+
+    #[test]
+    fn simple() {
+        let val = Resp::Simple("foo bar".to_owned());
+        let buf = Resp::ser(&val).unwrap();
+        assert_eq!(&buf, b"+foo bar\r\n");
+        let de = Resp::de(&buf).unwrap();
+        assert_eq!(de, val);
+    }
+
+    #[test]
+    fn error() {
+        let val = Resp::Error("foo bar".to_owned());
+        let buf = Resp::ser(&val).unwrap();
+        assert_eq!(&buf, b"-foo bar\r\n");
+        let de = Resp::de(&buf).unwrap();
+        assert_eq!(de, val);
+    }
+
+    #[test]
+    fn int() {
+        let val = Resp::Integer(1234567890);
+        let buf = Resp::ser(&val).unwrap();
+        assert_eq!(&buf, b":1234567890\r\n");
+        let de = Resp::de(&buf).unwrap();
+        assert_eq!(de, val);
+    }
+
+    #[test]
+    fn bulk() {
+        let mut buf = Vec::new();
+        buf.write_all(b"1234567890").unwrap();
+        let val = Resp::Bulk(buf);
+        let buf = Resp::ser(&val).unwrap();
+        assert_eq!(&buf, b"$10\r\n1234567890\r\n");
+        let de = Resp::de(&buf).unwrap();
+        assert_eq!(de, val);
+    }
+
+    #[test]
+    fn array() {
+        let mut buf = Vec::new();
+        buf.write_all(b"bulk").unwrap();
+        let val = Resp::Bulk(buf);
+
+        let val = Resp::Array(vec![
+            val,
+            Resp::Simple("str".to_owned()),
+            Resp::Error("err".to_owned()),
+            Resp::Integer(1),
+            Resp::NullBulk,
+            Resp::NullArray,
+        ]);
+        let val = Resp::Array(vec![
+            Resp::Simple("str".to_owned()),
+            Resp::Error("err".to_owned()),
+            val,
+            Resp::Integer(1),
+            Resp::NullBulk,
+            Resp::NullArray,
+        ]);
+        let buf = Resp::ser(&val).unwrap();
+        // println!("{:?}", String::from_utf8(buf.clone()).unwrap());
+        //assert_eq!(&buf, b":1234567890\r\n");
+        let de = Resp::de(&buf).unwrap();
+        assert_eq!(de, val);
+        let de = Resp::read_from(&mut &buf[..]).unwrap();
+        assert_eq!(de, val);
+    }
+
+    #[test]
+    fn resp3() {
+        let cases: Vec<(Resp, &[u8])> = vec![
+            (Resp::Null, b"_\r\n"),
+            (Resp::Double(1.5), b",1.5\r\n"),
+            (Resp::Double(f64::NEG_INFINITY), b",-inf\r\n"),
+            (Resp::Boolean(true), b"#t\r\n"),
+            (
+                Resp::BigNumber("-3492890328409238509324850943850943825024385".to_owned()),
+                b"(-3492890328409238509324850943850943825024385\r\n",
+            ),
+            (
+                Resp::Verbatim("txt".to_owned(), b"Some string".to_vec()),
+                b"=15\r\ntxt:Some string\r\n",
+            ),
+            (
+                Resp::Map(vec![(Resp::Simple("first".to_owned()), Resp::Integer(1))]),
+                b"%1\r\n+first\r\n:1\r\n",
+            ),
+            (
+                Resp::Set(vec![Resp::Boolean(false), Resp::Integer(2)]),
+                b"~2\r\n#f\r\n:2\r\n",
+            ),
+            (
+                Resp::Attribute(
+                    vec![(Resp::Simple("ttl".to_owned()), Resp::Integer(3600))],
+                    Box::new(Resp::Integer(42)),
+                ),
+                b"|1\r\n+ttl\r\n:3600\r\n:42\r\n",
+            ),
+            (
+                Resp::Push(vec![Resp::Simple("message".to_owned())]),
+                b">1\r\n+message\r\n",
+            ),
+        ];
+        for (val, expected) in cases {
+            let buf = val.ser().unwrap();
+            assert_eq!(&buf[..], expected);
+            assert_eq!(Resp::de(&buf).unwrap(), val);
+        }
+        assert!(Resp::de(b"#x\r\n").is_err());
+        assert!(Resp::de(b"(12a\r\n").is_err());
+        assert!(Resp::de(b"=3\r\ntxt\r\n").is_err());
+    }
+
+    #[test]
+    fn into_resp2() {
+        let val = Resp::Map(vec![
+            (Resp::Simple("a".to_owned()), Resp::Null),
+            (Resp::Simple("b".to_owned()), Resp::Boolean(true)),
+        ]);
+        assert_eq!(
+            val.into_resp2(),
+            Resp::Array(vec![
+                Resp::Simple("a".to_owned()),
+                Resp::NullBulk,
+                Resp::Simple("b".to_owned()),
+                Resp::Integer(1),
+            ])
+        );
+    }
+
+    fn sample() -> Resp {
+        Resp::Array(vec![
+            Resp::Bulk(b"SET".to_vec()),
+            Resp::Bulk(vec![b'x'; 10000]),
+            Resp::Array(vec![Resp::Integer(-1), Resp::NullBulk]),
+            Resp::Simple("OK".to_owned()),
+        ])
+    }
+
+    #[test]
+    fn de_truncated() {
+        let buf = sample().ser().unwrap();
+        for len in 0..buf.len() {
+            assert!(Resp::de(&buf[..len]).is_err());
+        }
+        assert!(Resp::de(b"$10\r\n1234\r\n").is_err());
+        assert!(Resp::de(b"$4\r\n123456\r\n").is_err());
+    }
+
+    #[test]
+    fn decode_byte_by_byte() {
+        let val = sample();
+        let buf = val.ser().unwrap();
+        let mut decoder = RespDecoder::new();
+        let mut decoded = Vec::new();
+        // two values back to back, fed one byte at a time
+        for &b in buf.iter().chain(buf.iter()) {
+            decoder.feed(&[b]);
+            if let Some(val) = decoder.decode().unwrap() {
+                decoded.push(val);
+            }
+        }
+        assert_eq!(decoded, vec![sample(), val]);
+        assert_eq!(decoder.decode().unwrap(), None);
+    }
+
+    #[test]
+    fn decode_from_reader() {
+        let mut buf = sample().ser().unwrap();
+        buf.extend(Resp::Integer(42).ser().unwrap());
+        // a tiny buffer makes every value span many reads
+        let mut reader = std::io::BufReader::with_capacity(7, &buf[..]);
+        let mut decoder = RespDecoder::new();
+        assert_eq!(decoder.read_from(&mut reader).unwrap(), Some(sample()));
+        assert_eq!(
+            decoder.read_from(&mut reader).unwrap(),
+            Some(Resp::Integer(42))
+        );
+        assert_eq!(decoder.read_from(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn nested_too_deep() {
+        let nested = |depth| {
+            let mut buf = b"*1\r\n".repeat(depth);
+            buf.extend_from_slice(b":1\r\n");
+            buf
+        };
+        assert!(Resp::de(&nested(MAX_DEPTH)).is_ok());
+        assert!(Resp::de(&nested(MAX_DEPTH + 1)).is_err());
+        // rejected long before the stack of a recursive parser would overflow
+        let mut decoder = RespDecoder::new();
+        let mut reader = &nested(1_000_000)[..];
+        assert!(decoder.read_from(&mut reader).is_err());
+    }
+
+    #[test]
+    fn decode_keeps_progress() {
+        let val = Resp::Array((0..100).map(|_| Resp::Bulk(vec![b'x'; 100])).collect());
+        let buf = val.ser().unwrap();
+        let mut decoder = RespDecoder::new();
+        for chunk in buf.chunks(3) {
+            assert_eq!(decoder.decode().unwrap(), None);
+            decoder.feed(chunk);
+            // only the element which is still incomplete is left undecoded
+            assert!(decoder.buf.len() - decoder.pos < 120);
+        }
+        assert_eq!(decoder.decode().unwrap(), Some(val));
+    }
+
+    #[test]
+    fn read_line_bounded() {
+        let mut reader = &b"PING\r\nGET key\r\n"[..];
+        assert_eq!(Resp::read_line(&mut reader).unwrap(), b"PING");
+        assert_eq!(Resp::read_line(&mut reader).unwrap(), b"GET key");
+        // a peer sending no newline is cut off instead of being buffered
+        let buf = vec![b'a'; 10 * MAX_LINE_LEN];
+        let mut reader = &buf[..];
+        assert!(Resp::read_line(&mut reader).is_err());
+        assert!(reader.len() >= 8 * MAX_LINE_LEN);
+    }
+
+    #[test]
+    fn read_truncated() {
+        let buf = b"*2\r\n$3\r\nfoo\r\n$10\r\nbar";
+        assert!(Resp::read_from(&mut &buf[..]).is_err());
+    }
+}
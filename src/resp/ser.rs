@@ -0,0 +1,331 @@
+use std::convert::TryFrom;
+
+use serde::ser::{self, Serialize};
+
+use super::Resp;
+use crate::{Error, Result};
+
+/// serializes rust values into a `Resp` tree
+///
+/// Strings become bulk strings, structs and maps RESP3 maps with the field names as keys
+/// and enum variants carrying data a map from the variant name to the data.
+pub(super) struct Serializer;
+
+pub(super) struct SerializeVec {
+    vec: Vec<Resp>,
+}
+
+pub(super) struct SerializeTupleVariant {
+    name: &'static str,
+    vec: Vec<Resp>,
+}
+
+pub(super) struct SerializeMap {
+    pairs: Vec<(Resp, Resp)>,
+    next_key: Option<Resp>,
+}
+
+pub(super) struct SerializeStructVariant {
+    name: &'static str,
+    pairs: Vec<(Resp, Resp)>,
+}
+
+fn bulk(s: &str) -> Resp {
+    Resp::Bulk(s.as_bytes().to_vec())
+}
+
+/// integers not fitting into a RESP integer are sent as big numbers
+fn integer<T: ToString>(i: T) -> Resp
+where
+    i64: TryFrom<T>,
+{
+    let repr = i.to_string();
+    match i64::try_from(i) {
+        Ok(i) => Resp::Integer(i),
+        Err(_) => Resp::BigNumber(repr),
+    }
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Resp;
+    type Error = Error;
+
+    type SerializeSeq = SerializeVec;
+    type SerializeTuple = SerializeVec;
+    type SerializeTupleStruct = SerializeVec;
+    type SerializeTupleVariant = SerializeTupleVariant;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeMap;
+    type SerializeStructVariant = SerializeStructVariant;
+
+    fn serialize_bool(self, v: bool) -> Result<Resp> {
+        Ok(Resp::Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Resp> {
+        Ok(Resp::Integer(v.into()))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Resp> {
+        Ok(Resp::Integer(v.into()))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Resp> {
+        Ok(Resp::Integer(v.into()))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Resp> {
+        Ok(Resp::Integer(v))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Resp> {
+        Ok(integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Resp> {
+        Ok(Resp::Integer(v.into()))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Resp> {
+        Ok(Resp::Integer(v.into()))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Resp> {
+        Ok(Resp::Integer(v.into()))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Resp> {
+        Ok(integer(v))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Resp> {
+        Ok(integer(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Resp> {
+        Ok(Resp::Double(v.into()))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Resp> {
+        Ok(Resp::Double(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Resp> {
+        Ok(bulk(v.encode_utf8(&mut [0; 4])))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Resp> {
+        Ok(bulk(v))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Resp> {
+        Ok(Resp::Bulk(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Resp> {
+        Ok(Resp::Null)
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Resp> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Resp> {
+        Ok(Resp::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Resp> {
+        Ok(Resp::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Resp> {
+        Ok(bulk(variant))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Resp> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Resp> {
+        Ok(Resp::Map(vec![(bulk(variant), value.serialize(self)?)]))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeVec> {
+        Ok(SerializeVec {
+            vec: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeVec> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SerializeVec> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeTupleVariant> {
+        Ok(SerializeTupleVariant {
+            name: variant,
+            vec: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeMap> {
+        Ok(SerializeMap {
+            pairs: Vec::with_capacity(len.unwrap_or(0)),
+            next_key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeMap> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeStructVariant> {
+        Ok(SerializeStructVariant {
+            name: variant,
+            pairs: Vec::with_capacity(len),
+        })
+    }
+}
+
+impl ser::SerializeSeq for SerializeVec {
+    type Ok = Resp;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.vec.push(value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Resp> {
+        Ok(Resp::Array(self.vec))
+    }
+}
+
+impl ser::SerializeTuple for SerializeVec {
+    type Ok = Resp;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Resp> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeVec {
+    type Ok = Resp;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Resp> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeTupleVariant {
+    type Ok = Resp;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.vec.push(value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Resp> {
+        Ok(Resp::Map(vec![(bulk(self.name), Resp::Array(self.vec))]))
+    }
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Resp;
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<()> {
+        self.next_key = Some(key.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| <Error as ser::Error>::custom("map value without a key"))?;
+        self.pairs.push((key, value.serialize(Serializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Resp> {
+        Ok(Resp::Map(self.pairs))
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap {
+    type Ok = Resp;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.pairs.push((bulk(key), value.serialize(Serializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Resp> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeStructVariant {
+    type Ok = Resp;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.pairs.push((bulk(key), value.serialize(Serializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Resp> {
+        Ok(Resp::Map(vec![(bulk(self.name), Resp::Map(self.pairs))]))
+    }
+}
@@ -0,0 +1,23 @@
+//! Thread pools used by the server to run connections concurrently
+
+use crate::Result;
+
+pub use naive::NaiveThreadPool;
+pub use shared_queue::SharedQueueThreadPool;
+
+mod naive;
+mod shared_queue;
+#[cfg(test)]
+mod tests;
+
+/// A pool of threads jobs can be handed to
+pub trait ThreadPool: Sized {
+    /// Create a pool with the given number of threads
+    fn new(threads: u32) -> Result<Self>;
+
+    /// Run the job on one of the threads of the pool.
+    /// A panicking job does not take the pool down with it.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
@@ -0,0 +1,21 @@
+use std::thread;
+
+use super::ThreadPool;
+use crate::Result;
+
+/// Starts a new thread for every job, the thread count is ignored
+#[derive(Debug)]
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(Self)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
@@ -0,0 +1,76 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::error;
+
+use super::ThreadPool;
+use crate::Result;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of threads taking jobs from one shared queue
+///
+/// A worker whose job panics is replaced by a fresh one, so the pool never shrinks.
+/// Dropping the pool lets the workers finish the queued jobs and exit.
+#[derive(Debug)]
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+/// a worker thread, respawned on drop if its job panicked
+struct Worker {
+    receiver: Arc<Mutex<Receiver<Job>>>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            Worker::spawn(Arc::clone(&receiver))?;
+        }
+        Ok(Self { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.sender.send(Box::new(job)).is_err() {
+            error!("all workers of the thread pool are gone");
+        }
+    }
+}
+
+impl Worker {
+    fn spawn(receiver: Arc<Mutex<Receiver<Job>>>) -> Result<()> {
+        thread::Builder::new()
+            .name("kvs-worker".to_owned())
+            .spawn(move || Worker { receiver }.run())?;
+        Ok(())
+    }
+
+    fn run(&self) {
+        loop {
+            // the lock is released before running the job
+            let job = self.receiver.lock().unwrap().recv();
+            match job {
+                Ok(job) => job(),
+                // the pool has been dropped
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            error!("job panicked, replacing worker");
+            if let Err(e) = Worker::spawn(Arc::clone(&self.receiver)) {
+                error!("failed to replace worker: {}", e);
+            }
+        }
+    }
+}
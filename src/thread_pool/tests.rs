@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+use super::*;
+use crate::Result;
+
+const THREADS: u32 = 4;
+const JOBS: usize = 32;
+
+/// run jobs on the pool and wait until all of them are done
+fn spawn_counter<P: ThreadPool>(pool: &P) {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..JOBS {
+        let counter = Arc::clone(&counter);
+        let sender = sender.clone();
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            sender.send(()).unwrap();
+        });
+    }
+    for _ in 0..JOBS {
+        receiver.recv().unwrap();
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), JOBS);
+}
+
+#[test]
+fn naive_thread_pool() -> Result<()> {
+    let pool = NaiveThreadPool::new(THREADS)?;
+    spawn_counter(&pool);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(THREADS)?;
+    spawn_counter(&pool);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_panic() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(THREADS)?;
+    // more panics than threads, the pool would be empty without respawning
+    for _ in 0..THREADS * 2 {
+        pool.spawn(|| panic!("job panicked"));
+    }
+    spawn_counter(&pool);
+    Ok(())
+}
@@ -1,10 +1,11 @@
 use std::fs;
+use std::ops::{Bound, RangeBounds};
 use std::path::Path;
 
 use serde::{Deserialize, Serialize};
 use simplelog::*;
 
-use crate::Result;
+use crate::{Result, Transaction, WriteBatch};
 
 /// helper to init the logger
 pub fn logger(file: impl AsRef<Path>) -> Result<()> {
@@ -19,22 +20,139 @@ pub fn logger(file: impl AsRef<Path>) -> Result<()> {
     Ok(())
 }
 
+/// whether the expiry, if there is one, had passed at the given unix time in milliseconds
+pub(crate) fn is_expired_at(expires: Option<u64>, now: u64) -> bool {
+    match expires {
+        Some(expires) => expires <= now,
+        None => false,
+    }
+}
+
+/// the smallest key greater than all keys starting with the prefix,
+/// `None` if there is no such key
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let len = prefix.iter().rposition(|&b| b != u8::MAX)?;
+    let mut end = prefix[..=len].to_vec();
+    end[len] += 1;
+    Some(end)
+}
+
+/// the smallest key greater than the given one
+pub(crate) fn successor(key: &[u8]) -> Vec<u8> {
+    let mut next = Vec::with_capacity(key.len() + 1);
+    next.extend_from_slice(key);
+    next.push(0);
+    next
+}
+
+/// the half open range `start..end` covering the same keys, `None` is unbounded
+pub(crate) fn half_open(range: &impl RangeBounds<Vec<u8>>) -> (Vec<u8>, Option<Vec<u8>>) {
+    let start = match range.start_bound() {
+        Bound::Included(start) => start.clone(),
+        Bound::Excluded(start) => successor(start),
+        Bound::Unbounded => Vec::new(),
+    };
+    let end = match range.end_bound() {
+        Bound::Included(end) => Some(successor(end)),
+        Bound::Excluded(end) => Some(end.clone()),
+        Bound::Unbounded => None,
+    };
+    (start, end)
+}
+
+/// first frame sent by `KvsClient`, before any request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    /// protocol version spoken by the client
+    pub version: u32,
+}
+
+/// answer of the server to `Hello`
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Welcome {
+    /// the server speaks the version of the client
+    Accepted,
+    /// the server cannot talk to the client, it closes the connection
+    Rejected(String),
+}
+
 /// command sent between client and server
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
     /// get key
-    Get(String),
+    Get(Vec<u8>),
     /// update key value pair
-    Set(String, String),
+    Set(Vec<u8>, Vec<u8>),
     /// remove key value pair
-    Rm(String),
+    Rm(Vec<u8>),
+    /// list at most `count` pairs with keys in `start..end` in ascending order,
+    /// an `end` of `None` is unbounded
+    Scan {
+        /// first key to list
+        start: Vec<u8>,
+        /// the keys listed are smaller
+        end: Option<Vec<u8>>,
+        /// upper limit of pairs, the server may return fewer
+        count: u32,
+    },
+    /// apply the operations atomically
+    Batch(WriteBatch),
+    /// set the key to `new` if its value is still `expected`, `None` meaning absent
+    Cas {
+        /// key to swap
+        key: Vec<u8>,
+        /// value the key must have
+        expected: Option<Vec<u8>>,
+        /// value to set, `None` removes the key
+        new: Option<Vec<u8>>,
+    },
+    /// set a key which does not exist yet
+    SetIfAbsent(Vec<u8>, Vec<u8>),
+    /// set a key only if it exists
+    SetIfPresent(Vec<u8>, Vec<u8>),
+    /// get the value of a key along with its version
+    GetVersioned(Vec<u8>),
+    /// apply the writes unless a watched key changed
+    Commit(Transaction),
+    /// set a key which expires after the given number of milliseconds
+    SetWithTtl(Vec<u8>, Vec<u8>, u64),
+    /// make an existing key expire after the given number of milliseconds
+    Expire(Vec<u8>, u64),
+    /// get the milliseconds a key has left to live
+    Ttl(Vec<u8>),
+    /// keep a key from expiring
+    Persist(Vec<u8>),
 }
 
 /// respond from server
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Respond {
-    /// failed
-    Err(String),
     /// the data is retrived
-    Ok(Option<String>),
+    Ok(Option<Vec<u8>>),
+    /// failed, the code identifies the `ErrorKind` and the message describes the cause
+    Error {
+        /// stable code of the error kind
+        code: u32,
+        /// cause of the error, may be empty
+        message: String,
+    },
+    /// a page of scanned pairs, `next` is the `start` of the following page
+    /// and `None` once the range is exhausted
+    Scan {
+        /// pairs in ascending key order
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+        /// cursor to continue the scan from
+        next: Option<Vec<u8>>,
+    },
+    /// whether a conditional write took place
+    Swapped(bool),
+    /// the value of a key and its version
+    Versioned {
+        /// value, `None` if the key does not exist
+        value: Option<Vec<u8>>,
+        /// version of the key, 0 if it does not exist
+        version: u64,
+    },
+    /// milliseconds a key has left to live, `None` if it does not expire
+    Ttl(Option<u64>),
 }
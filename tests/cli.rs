@@ -213,6 +213,56 @@ fn cli_wrong_engine() {
     }
 }
 
+#[test]
+fn cli_unknown_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--engine", "unknown", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown engine"));
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
+#[test]
+fn cli_wrong_pool() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&[
+        "--engine",
+        "kvs",
+        "--pool",
+        "unknown",
+        "--addr",
+        "127.0.0.1:4006",
+    ])
+    .current_dir(&temp_dir)
+    .assert()
+    .failure()
+    .stderr(contains("'unknown' isn't a valid value for '--pool"));
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
+#[test]
+fn cli_wrong_protocol() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&[
+        "--engine",
+        "kvs",
+        "--protocol",
+        "unknown",
+        "--addr",
+        "127.0.0.1:4008",
+    ])
+    .current_dir(&temp_dir)
+    .assert()
+    .failure()
+    .stderr(contains("'unknown' isn't a valid value for '--protocol"));
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -225,6 +275,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // the next server opens the same database, which stays locked until this one is gone
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -299,6 +351,111 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(contains("Key not found"));
 
+    // conditional writes fail when their condition does not hold
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value1", "--if-present", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Condition not met"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value1", "--if-absent", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value2", "--if-absent", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Condition not met"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "cas",
+            "key3",
+            "--expected",
+            "value1",
+            "--new",
+            "value4",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "key3", "--new", "value5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Condition not met"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value4\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "key3", "--expected", "value4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "set",
+            "key3",
+            "value1",
+            "--if-absent",
+            "--if-present",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key4", "value1", "--px", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key4", "value1", "--ex", "0", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    thread::sleep(Duration::from_millis(200));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -343,3 +500,48 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs scan` lists pairs in key order, optionally limited to a prefix or a range.
+#[test]
+fn cli_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    for (key, value) in &[("b1", "v2"), ("a1", "v1"), ("b2", "v3"), ("c1", "v4")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(&["set", key, value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a1\tv1\nb1\tv2\nb2\tv3\nc1\tv4\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "b"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("b1\tv2\nb2\tv3\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "--start", "a2", "--end", "c1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("b1\tv2\nb2\tv3\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "b", "--start", "a"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
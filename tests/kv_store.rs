@@ -1,4 +1,14 @@
-use kvs::{KvStore, KvsEngine, Result};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use fs2::FileExt;
+use kvs::{
+    Clock, Durability, ErrorKind, KvStore, KvStoreOptions, KvsEngine, Result, SledKvsEngine,
+    Snapshot, Transaction, WriteBatch,
+};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +16,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +26,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +37,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +46,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +58,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,7 +74,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,7 +82,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,7 +94,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +124,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +134,1090 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// A crash in the middle of an append should not make earlier records unreadable
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut logs: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("kvs".as_ref()))
+        .collect();
+    logs.sort();
+    let mut last = logs.pop().unwrap();
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&last)?
+        .write_all(&[0x42, 0x42, 0x42])?;
+    last.set_extension("hint");
+    fs::remove_file(&last)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// The active segment should be sealed and replaced once it grows past the size limit
+#[test]
+fn segment_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        segment_size: 1024,
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let logs: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("kvs".as_ref()))
+        .collect();
+    assert!(logs.len() > 1);
+    for log in logs {
+        let metadata = fs::metadata(&log)?;
+        // a record is appended before the size is checked
+        assert!(metadata.len() < 1024 + 64);
+        if metadata.len() >= 1024 {
+            assert!(metadata.permissions().readonly());
+        }
+    }
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Opening the store again should not leave new files behind
+#[test]
+fn reopen_reuses_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file_count = || fs::read_dir(temp_dir.path()).unwrap().count();
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let count = file_count();
+
+    for _ in 0..10 {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(store);
+        assert_eq!(file_count(), count);
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert_eq!(file_count(), count);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A full tail segment is sealed on open instead of being appended to
+#[test]
+fn reopen_full_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let options = KvStoreOptions {
+        segment_size: 1024,
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    drop(store);
+
+    let logs = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("kvs".as_ref()))
+        .count();
+    assert_eq!(logs, 2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..=100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Writes issued while the background compaction is running must not be lost
+#[test]
+fn compaction_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        segment_size: 4096,
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for iter in 0..20 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        store.remove(format!("key{}", iter))?;
+    }
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..1000 {
+            let expected = if key_id == 19 {
+                None
+            } else {
+                Some("19".to_owned())
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check(&store)?;
+
+    Ok(())
+}
+
+fn log_files(dir: &Path) -> Vec<PathBuf> {
+    let mut logs: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("kvs".as_ref()))
+        .collect();
+    logs.sort();
+    logs
+}
+
+// Only segments with enough garbage are rewritten, cold data stays where it is
+#[test]
+fn compaction_selects_garbage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        segment_size: 1024,
+        garbage_ratio: 0.5,
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for key_id in 0..200 {
+        store.set(format!("cold{}", key_id), format!("value{}", key_id))?;
+    }
+    let mut cold = log_files(temp_dir.path());
+    // the active segment receives hot keys as well
+    cold.pop();
+    assert!(cold.len() > 1);
+
+    for iter in 0..2000 {
+        store.set(format!("hot{}", iter % 10), format!("{}", iter))?;
+    }
+    drop(store);
+
+    let logs = log_files(temp_dir.path());
+    for seg in &cold {
+        assert!(logs.contains(seg), "cold segment {:?} compacted", seg);
+    }
+    assert!(logs.len() < cold.len() + 10);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("cold{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("hot{}", key_id))?,
+            Some(format!("{}", 1990 + key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Removed keys are garbage and their segments get compacted away
+#[test]
+fn compaction_reclaims_removed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        segment_size: 1024,
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let mut written = log_files(temp_dir.path());
+    written.pop();
+    for key_id in 0..200 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    drop(store);
+
+    let logs = log_files(temp_dir.path());
+    assert!(written.iter().any(|seg| !logs.contains(seg)));
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+
+    Ok(())
+}
+
+// Files not listed in the manifest are left over by an interrupted compaction
+// and should be removed on open.
+#[test]
+fn manifest_removes_orphans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("MANIFEST").is_file());
+
+    let orphan = temp_dir.path().join("0-0000.kvs");
+    fs::File::create(&orphan)
+        .unwrap()
+        .write_all(b"garbage")
+        .unwrap();
+    let tmp = temp_dir.path().join("MANIFEST.tmp");
+    fs::File::create(&tmp).unwrap();
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!orphan.exists());
+    assert!(!tmp.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Directories written before the manifest existed are still readable.
+#[test]
+fn manifest_missing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        segment_size: 256,
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    fs::remove_file(temp_dir.path().join("MANIFEST")).unwrap();
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..50 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert!(temp_dir.path().join("MANIFEST").is_file());
+
+    Ok(())
+}
+
+#[test]
+fn manifest_rejects_unknown_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut manifest = fs::File::create(temp_dir.path().join("MANIFEST")).unwrap();
+    // version followed by an empty segment list
+    manifest.write_all(&99u32.to_le_bytes()).unwrap();
+    manifest.write_all(&0u64.to_le_bytes()).unwrap();
+    drop(manifest);
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn manifest_missing_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    for log in log_files(temp_dir.path()) {
+        fs::remove_file(log).unwrap();
+    }
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+// Clones of a store share the same data and can be used from several threads at once.
+#[test]
+fn concurrent_clones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        segment_size: 1024,
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    let key = format!("key{}-{}", t, i);
+                    store.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for t in 0..8 {
+            for i in 0..100 {
+                let key = format!("key{}-{}", t, i);
+                assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check(&store)?;
+
+    Ok(())
+}
+
+/// engines the checks below run against, opened with a clock the test controls
+trait TestEngine: KvsEngine {
+    fn open_with_clock(dir: &Path, clock: Clock) -> Result<Self>;
+
+    /// wait until the directory can be opened again, once every handle is dropped
+    fn wait_closed(_dir: &Path) {}
+}
+
+impl TestEngine for KvStore {
+    fn open_with_clock(dir: &Path, clock: Clock) -> Result<Self> {
+        KvStore::open_with_clock(dir, KvStoreOptions::default(), clock)
+    }
+}
+
+impl TestEngine for SledKvsEngine {
+    fn open_with_clock(dir: &Path, clock: Clock) -> Result<Self> {
+        SledKvsEngine::open_with_clock(dir, clock)
+    }
+
+    // sled's io threads let go of its lock a moment after the last handle,
+    // blocking on the lock is what sled's own tests do before reopening
+    fn wait_closed(dir: &Path) {
+        let lock = fs::File::open(dir.join("db")).expect("unable to open the sled lock file");
+        lock.lock_exclusive()
+            .expect("unable to wait for the sled lock");
+        lock.unlock().expect("unable to release the sled lock");
+    }
+}
+
+/// a test per check and engine, in a module named after the engine
+macro_rules! engine_tests {
+    ($($check:ident),* $(,)?) => {
+        engine_tests!(@engine kvs_store, KvStore, $($check),*);
+        engine_tests!(@engine sled_engine, SledKvsEngine, $($check),*);
+    };
+    (@engine $module:ident, $engine:ty, $($check:ident),*) => {
+        mod $module {
+            use super::*;
+
+            $(
+                #[test]
+                fn $check() -> Result<()> {
+                    let temp_dir =
+                        TempDir::new().expect("unable to create temporary working directory");
+                    super::$check::<$engine>(temp_dir.path())
+                }
+            )*
+        }
+    };
+}
+
+engine_tests!(
+    binary_keys_and_values,
+    scan,
+    write_batch,
+    compare_and_swap,
+    transaction,
+    ttl,
+);
+
+// Keys and values do not have to be UTF-8, for either engine.
+fn binary_keys_and_values<E: TestEngine>(dir: &Path) -> Result<()> {
+    let key = vec![0, 159, 146, 150, 255];
+    let value: Vec<u8> = (0..=255).rev().collect();
+    let engine = E::open(dir)?;
+    engine.set_bytes(key.clone(), value.clone())?;
+    engine.set_bytes(b"key1".to_vec(), value.clone())?;
+    engine.set_bytes(Vec::new(), Vec::new())?;
+    assert_eq!(engine.get_bytes(key.clone())?, Some(value.clone()));
+    assert_eq!(engine.get_bytes(Vec::new())?, Some(Vec::new()));
+
+    // the string API refuses values it cannot represent
+    let e = engine.get("key1".to_owned()).unwrap_err();
+    assert!(matches!(e.kind(), ErrorKind::Encoding));
+    drop(engine);
+    E::wait_closed(dir);
+
+    let engine = E::open(dir)?;
+    assert_eq!(engine.get_bytes(key.clone())?, Some(value));
+    engine.remove_bytes(key.clone())?;
+    assert_eq!(engine.get_bytes(key)?, None);
+    Ok(())
+}
+
+// Scans list pairs in key order, for either engine.
+fn scan<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open(dir)?;
+    for i in (0..1000).rev() {
+        engine.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    engine.set("other".to_owned(), "value".to_owned())?;
+    engine.remove("key0500".to_owned())?;
+    engine.set_bytes(b"key\xff".to_vec(), b"last".to_vec())?;
+
+    let keys = |scan: kvs::Scan| -> Result<Vec<Vec<u8>>> {
+        scan.map(|pair| pair.map(|(key, _)| key)).collect()
+    };
+    let all = keys(engine.scan(..)?)?;
+    assert_eq!(all.len(), 1000 + 1);
+    assert!(all.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(all[0], b"key0000");
+    assert_eq!(all[1000], b"other");
+
+    let range: Vec<_> = engine
+        .scan(b"key0498".to_vec()..=b"key0502".to_vec())?
+        .collect::<Result<_>>()?;
+    let expected: Vec<_> = [498, 499, 501, 502]
+        .iter()
+        .map(|i| {
+            let pair = (format!("key{:04}", i), format!("value{}", i));
+            (pair.0.into_bytes(), pair.1.into_bytes())
+        })
+        .collect();
+    assert_eq!(range, expected);
+
+    let prefixed = keys(engine.scan_prefix(b"key09".to_vec())?)?;
+    assert_eq!(prefixed.len(), 100);
+    assert_eq!(prefixed[0], b"key0900");
+    let prefixed = keys(engine.scan_prefix(b"key".to_vec())?)?;
+    assert_eq!(prefixed.len(), 1000);
+    assert_eq!(prefixed[999], b"key\xff");
+
+    assert!(keys(engine.scan(b"z".to_vec()..)?)?.is_empty());
+    assert!(keys(engine.scan(b"b".to_vec()..b"a".to_vec())?)?.is_empty());
+    Ok(())
+}
+
+// Batches are applied entirely or not at all, for either engine.
+fn write_batch<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open(dir)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2", "value2")
+        .remove("key1")
+        .set("key3", "value3")
+        .set("key3", "value4");
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    // removing a missing key fails the whole batch
+    let mut batch = WriteBatch::new();
+    batch.set("key4", "value4").remove("key2").remove("key2");
+    let e = engine.write_batch(batch).unwrap_err();
+    assert!(matches!(e.kind(), ErrorKind::KeyNotExist));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key4".to_owned())?, None);
+
+    // keys set earlier in the batch can be removed
+    let mut batch = WriteBatch::new();
+    batch.set("key5", "value5").remove("key5");
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get("key5".to_owned())?, None);
+    engine.write_batch(WriteBatch::new())?;
+    drop(engine);
+    E::wait_closed(dir);
+
+    let engine = E::open(dir)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(engine.get("key4".to_owned())?, None);
+    assert_eq!(engine.get("key5".to_owned())?, None);
+    Ok(())
+}
+
+// A batch cut off by a crash is dropped as a whole.
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut last = log_files(temp_dir.path()).pop().unwrap();
+    let size = fs::metadata(&last)?.len();
+
+    let store = KvStore::open(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    for i in 0..100 {
+        batch.set(format!("batched{}", i), "value");
+    }
+    store.write_batch(batch)?;
+    drop(store);
+    let torn = size + (fs::metadata(&last)?.len() - size) * 9 / 10;
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&last)?
+        .set_len(torn)?;
+    last.set_extension("hint");
+    fs::remove_file(&last)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    for i in 0..100 {
+        assert_eq!(store.get(format!("batched{}", i))?, None);
+    }
+
+    Ok(())
+}
+
+// Conditional writes only apply when the current value matches, for either engine.
+fn compare_and_swap<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open(dir)?;
+    let bytes = |s: &str| Some(s.as_bytes().to_vec());
+
+    assert!(engine.compare_and_swap(b"key1".to_vec(), None, bytes("value1"))?);
+    assert!(!engine.compare_and_swap(b"key1".to_vec(), None, bytes("value2"))?);
+    assert!(!engine.compare_and_swap(b"key1".to_vec(), bytes("value2"), bytes("value3"))?);
+    assert!(engine.compare_and_swap(b"key1".to_vec(), bytes("value1"), bytes("value2"))?);
+    assert_eq!(engine.get_bytes(b"key1".to_vec())?, bytes("value2"));
+    assert!(engine.compare_and_swap(b"key1".to_vec(), bytes("value2"), None)?);
+    assert_eq!(engine.get_bytes(b"key1".to_vec())?, None);
+    assert!(engine.compare_and_swap(b"key1".to_vec(), None, None)?);
+
+    assert!(engine.set_if_absent(b"key2".to_vec(), b"value1".to_vec())?);
+    assert!(!engine.set_if_absent(b"key2".to_vec(), b"value2".to_vec())?);
+    assert!(engine.set_if_present(b"key2".to_vec(), b"value3".to_vec())?);
+    assert!(!engine.set_if_present(b"key3".to_vec(), b"value1".to_vec())?);
+    assert_eq!(engine.get_bytes(b"key2".to_vec())?, bytes("value3"));
+    assert_eq!(engine.get_bytes(b"key3".to_vec())?, None);
+
+    // conditional writes drop the time to live like any other set
+    let hour = Duration::from_secs(3600);
+    engine.set_with_ttl(b"key4".to_vec(), b"value1".to_vec(), hour)?;
+    assert!(engine.compare_and_swap(b"key4".to_vec(), bytes("value1"), bytes("value2"))?);
+    assert_eq!(engine.ttl(b"key4".to_vec())?, None);
+    assert!(engine.expire(b"key4".to_vec(), hour)?);
+    assert!(engine.set_if_present(b"key4".to_vec(), b"value3".to_vec())?);
+    assert_eq!(engine.ttl(b"key4".to_vec())?, None);
+
+    // concurrent increments never lose an update
+    engine.set("counter".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let current = engine.get_bytes(b"counter".to_vec())?.unwrap();
+                        let n: u32 = String::from_utf8(current.clone()).unwrap().parse().unwrap();
+                        let next = (n + 1).to_string().into_bytes();
+                        if engine.compare_and_swap(
+                            b"counter".to_vec(),
+                            Some(current),
+                            Some(next),
+                        )? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get("counter".to_owned())?, Some("200".to_owned()));
+
+    drop(engine);
+    E::wait_closed(dir);
+    let engine = E::open(dir)?;
+    assert_eq!(engine.get_bytes(b"key1".to_vec())?, None);
+    assert_eq!(engine.get_bytes(b"key2".to_vec())?, bytes("value3"));
+    assert_eq!(engine.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+/// move one unit from one account to the other, retrying on conflicts
+fn transfer<E: KvsEngine>(engine: &E, from: &str, to: &str) -> Result<()> {
+    loop {
+        let mut txn = Transaction::new();
+        let balance = |value: Option<Vec<u8>>| -> u32 {
+            String::from_utf8(value.unwrap()).unwrap().parse().unwrap()
+        };
+        let a = balance(engine.get_watched(&mut txn, from.into())?);
+        let b = balance(engine.get_watched(&mut txn, to.into())?);
+        txn.set(from, (a - 1).to_string())
+            .set(to, (b + 1).to_string());
+        match engine.commit(txn) {
+            Ok(()) => return Ok(()),
+            Err(e) if matches!(e.kind(), ErrorKind::Conflict) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Transactions only commit if the keys they read are unchanged, for either engine.
+fn transaction<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open(dir)?;
+    assert_eq!(engine.get_versioned(b"key1".to_vec())?, (None, 0));
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let (value, version) = engine.get_versioned(b"key1".to_vec())?;
+    assert_eq!(value, Some(b"value1".to_vec()));
+    assert_ne!(version, 0);
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_ne!(engine.get_versioned(b"key1".to_vec())?.1, version);
+
+    // unchanged keys let the writes through
+    let mut txn = Transaction::new();
+    assert_eq!(
+        engine.get_watched(&mut txn, b"key1".to_vec())?,
+        Some(b"value2".to_vec())
+    );
+    assert_eq!(engine.get_watched(&mut txn, b"key2".to_vec())?, None);
+    txn.set("key2", "value1").remove("key1");
+    engine.commit(txn)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    // a watched key written in between makes the commit fail
+    let mut txn = Transaction::new();
+    engine.get_watched(&mut txn, b"key2".to_vec())?;
+    txn.set("key3", "value1");
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    let e = engine.commit(txn).unwrap_err();
+    assert!(matches!(e.kind(), ErrorKind::Conflict));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+
+    // as do keys removed or created in between
+    let mut txn = Transaction::new();
+    engine.get_watched(&mut txn, b"key2".to_vec())?;
+    engine.remove("key2".to_owned())?;
+    txn.set("key3", "value1");
+    assert!(matches!(
+        engine.commit(txn).unwrap_err().kind(),
+        ErrorKind::Conflict
+    ));
+    let mut txn = Transaction::new();
+    engine.get_watched(&mut txn, b"key2".to_vec())?;
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+    txn.set("key3", "value1");
+    assert!(matches!(
+        engine.commit(txn).unwrap_err().kind(),
+        ErrorKind::Conflict
+    ));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+
+    // writing back the value a key had still counts as a change
+    let mut txn = Transaction::new();
+    engine.get_watched(&mut txn, b"key2".to_vec())?;
+    engine.set("key2".to_owned(), "value4".to_owned())?;
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+    txn.set("key3", "value1");
+    assert!(matches!(
+        engine.commit(txn).unwrap_err().kind(),
+        ErrorKind::Conflict
+    ));
+
+    // concurrent transfers neither lose nor create money
+    engine.set("alice".to_owned(), "1000".to_owned())?;
+    engine.set("bob".to_owned(), "1000".to_owned())?;
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                let (from, to) = if t % 2 == 0 {
+                    ("alice", "bob")
+                } else {
+                    ("bob", "alice")
+                };
+                for _ in 0..50 {
+                    transfer(&engine, from, to)?;
+                }
+                transfer(&engine, "alice", "bob")
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get("alice".to_owned())?, Some("996".to_owned()));
+    assert_eq!(engine.get("bob".to_owned())?, Some("1004".to_owned()));
+
+    // versions seen before a restart are not handed out again
+    let mut txn = Transaction::new();
+    engine.get_watched(&mut txn, b"alice".to_vec())?;
+    drop(engine);
+    E::wait_closed(dir);
+    let engine = E::open(dir)?;
+    assert_eq!(engine.get("alice".to_owned())?, Some("996".to_owned()));
+    assert_eq!(engine.get("bob".to_owned())?, Some("1004".to_owned()));
+    engine.set("alice".to_owned(), "0".to_owned())?;
+    txn.set("bob", "0");
+    assert!(matches!(
+        engine.commit(txn).unwrap_err().kind(),
+        ErrorKind::Conflict
+    ));
+    Ok(())
+}
+
+// Snapshots keep seeing the store as it was when they were taken.
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Clock::manual();
+    let store =
+        KvStore::open_with_clock(temp_dir.path(), KvStoreOptions::default(), clock.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let snapshot = store.snapshot();
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.set("key2".to_owned(), "value5".to_owned())?;
+    let later = store.snapshot();
+    store.set("key2".to_owned(), "value6".to_owned())?;
+    assert!(later.seq() > snapshot.seq());
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+    assert_eq!(later.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(later.get("key2".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value6".to_owned()));
+
+    let pairs = |snapshot: &Snapshot| -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        snapshot.scan_prefix(b"key".to_vec())?.collect()
+    };
+    assert_eq!(
+        pairs(&snapshot)?,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+        ]
+    );
+    drop(snapshot);
+    assert_eq!(
+        pairs(&later)?,
+        vec![
+            (b"key1".to_vec(), b"value3".to_vec()),
+            (b"key2".to_vec(), b"value5".to_vec()),
+        ]
+    );
+
+    // keys expiring after the snapshot was taken stay in it, even once swept
+    store.set_with_ttl(
+        b"short".to_vec(),
+        b"lived".to_vec(),
+        Duration::from_millis(50),
+    )?;
+    let snapshot = store.snapshot();
+    clock.advance(Duration::from_millis(50));
+    store.sweep()?;
+    assert_eq!(store.get_bytes(b"short".to_vec())?, None);
+    assert_eq!(
+        snapshot.get_bytes(b"short".to_vec())?,
+        Some(b"lived".to_vec())
+    );
+    assert_eq!(snapshot.scan_prefix(b"short".to_vec())?.count(), 1);
+    drop(snapshot);
+
+    // scans span several batches, even when most keys are gone by now
+    for key_id in 0..1000 {
+        store.set(format!("many{:04}", key_id), format!("value{}", key_id))?;
+    }
+    let snapshot = store.snapshot();
+    let scan = snapshot.scan_prefix(b"many".to_vec())?;
+    drop(snapshot);
+    for key_id in 0..1000 {
+        if key_id % 10 != 0 {
+            store.remove(format!("many{:04}", key_id))?;
+        }
+    }
+    assert_eq!(scan.count(), 1000);
+    assert_eq!(store.scan_prefix(b"many".to_vec())?.count(), 100);
+
+    Ok(())
+}
+
+// Segments a snapshot reads from survive compaction until the snapshot is dropped.
+#[test]
+fn snapshot_pins_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        segment_size: 1024,
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let mut written = log_files(temp_dir.path());
+    written.pop();
+    let snapshot = store.snapshot();
+    for key_id in 0..200 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    for iter in 0..2000 {
+        store.set(format!("hot{}", iter % 10), format!("{}", iter))?;
+    }
+    store.wait_compaction();
+
+    let logs = log_files(temp_dir.path());
+    for seg in &written {
+        assert!(logs.contains(seg), "pinned segment {:?} deleted", seg);
+    }
+    for key_id in 0..200 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+
+    drop(snapshot);
+    drop(store);
+    let logs = log_files(temp_dir.path());
+    assert!(written.iter().any(|seg| !logs.contains(seg)));
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    assert_eq!(store.get("hot9".to_owned())?, Some("1999".to_owned()));
+
+    Ok(())
+}
+
+// Keys with a time to live vanish once it has passed, for either engine.
+fn ttl<E: TestEngine>(dir: &Path) -> Result<()> {
+    let clock = Clock::manual();
+    let engine = E::open_with_clock(dir, clock.clone())?;
+    let bytes = |s: &str| Some(s.as_bytes().to_vec());
+    let hour = Duration::from_secs(3600);
+
+    engine.set_with_ttl(
+        b"short".to_vec(),
+        b"value1".to_vec(),
+        Duration::from_millis(200),
+    )?;
+    engine.set_with_ttl(b"long".to_vec(), b"value2".to_vec(), hour)?;
+    engine.set_bytes(b"plain".to_vec(), b"value3".to_vec())?;
+    let mut batch = WriteBatch::new();
+    batch.set_with_ttl(
+        b"batched".to_vec(),
+        b"value4".to_vec(),
+        Duration::from_millis(200),
+    );
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get_bytes(b"short".to_vec())?, bytes("value1"));
+    assert_eq!(engine.get_bytes(b"batched".to_vec())?, bytes("value4"));
+    assert_eq!(engine.ttl(b"long".to_vec())?, Some(hour));
+    assert_eq!(engine.ttl(b"plain".to_vec())?, None);
+    let e = engine.ttl(b"missing".to_vec()).unwrap_err();
+    assert!(matches!(e.kind(), ErrorKind::KeyNotExist));
+
+    assert!(engine.expire(b"plain".to_vec(), hour)?);
+    assert!(engine.ttl(b"plain".to_vec())?.is_some());
+    assert!(!engine.expire(b"missing".to_vec(), hour)?);
+    assert!(engine.persist(b"plain".to_vec())?);
+    assert!(!engine.persist(b"plain".to_vec())?);
+    assert!(!engine.persist(b"missing".to_vec())?);
+    assert_eq!(engine.ttl(b"plain".to_vec())?, None);
+    assert_eq!(engine.get_bytes(b"plain".to_vec())?, bytes("value3"));
+
+    // overwriting a key drops its time to live
+    engine.set_with_ttl(
+        b"reset".to_vec(),
+        b"value1".to_vec(),
+        Duration::from_millis(200),
+    )?;
+    engine.set_bytes(b"reset".to_vec(), b"value2".to_vec())?;
+
+    clock.advance(Duration::from_millis(200));
+    assert_eq!(engine.get_bytes(b"short".to_vec())?, None);
+    assert_eq!(engine.get_bytes(b"batched".to_vec())?, None);
+    assert_eq!(engine.get_bytes(b"reset".to_vec())?, bytes("value2"));
+    let keys: Vec<_> = engine
+        .scan(..)?
+        .map(|res| res.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        keys,
+        vec![b"long".to_vec(), b"plain".to_vec(), b"reset".to_vec()]
+    );
+    let e = engine.remove_bytes(b"short".to_vec()).unwrap_err();
+    assert!(matches!(e.kind(), ErrorKind::KeyNotExist));
+    assert!(engine.set_if_absent(b"short".to_vec(), b"value5".to_vec())?);
+    assert_eq!(engine.ttl(b"short".to_vec())?, None);
+
+    // expiries survive a restart
+    engine.set_with_ttl(
+        b"restart".to_vec(),
+        b"value6".to_vec(),
+        Duration::from_millis(200),
+    )?;
+    drop(engine);
+    E::wait_closed(dir);
+    let engine = E::open_with_clock(dir, clock.clone())?;
+    assert!(engine.ttl(b"long".to_vec())?.is_some());
+    assert_eq!(engine.ttl(b"plain".to_vec())?, None);
+    assert_eq!(engine.get_bytes(b"short".to_vec())?, bytes("value5"));
+    clock.advance(Duration::from_millis(200));
+    assert_eq!(engine.get_bytes(b"restart".to_vec())?, None);
+    assert_eq!(engine.get_bytes(b"long".to_vec())?, bytes("value2"));
+    Ok(())
+}
+
+// Expired keys are removed in the background and their segments compacted away
+#[test]
+fn expired_keys_reclaimed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Clock::manual();
+    let options = KvStoreOptions {
+        segment_size: 1024,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_clock(temp_dir.path(), options.clone(), clock.clone())?;
+
+    for key_id in 0..1000 {
+        let (key, value) = (format!("key{}", key_id), format!("value{}", key_id));
+        store.set_with_ttl(
+            key.into_bytes(),
+            value.into_bytes(),
+            Duration::from_millis(50),
+        )?;
+    }
+    let mut written = log_files(temp_dir.path());
+    written.pop();
+    // nothing but the sweep makes the expired values garbage
+    clock.advance(Duration::from_millis(50));
+    store.sweep()?;
+    // the tombstones rotate the active segment like any other write,
+    // the size is checked after each batch of them
+    for log in log_files(temp_dir.path()) {
+        assert!(fs::metadata(&log)?.len() < 1024 + 128 * 64);
+    }
+    for iter in 0..100 {
+        store.set(format!("hot{}", iter % 10), format!("{}", iter))?;
+    }
+    drop(store);
+
+    let logs = log_files(temp_dir.path());
+    assert!(written.iter().any(|seg| !logs.contains(seg)));
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    assert_eq!(store.get("hot9".to_owned())?, Some("99".to_owned()));
+
+    Ok(())
+}
+
+// Every durability keeps the writes across a reopen, syncing writes the active hint back.
+#[test]
+fn durability() -> Result<()> {
+    let hint_written = |dir: &Path| {
+        log_files(dir)
+            .last()
+            .unwrap()
+            .with_extension("hint")
+            .exists()
+    };
+    let policies = [
+        Durability::Always,
+        Durability::Periodic(Duration::from_millis(10)),
+        Durability::Os,
+    ];
+    for &durability in &policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            durability,
+            ..Default::default()
+        };
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove("key0".to_owned())?;
+        let mut batch = WriteBatch::new();
+        batch.set("key100", "value100");
+        store.write_batch(batch)?;
+
+        // the background syncer leaves the hint alone, it is only written when asked
+        assert!(!hint_written(temp_dir.path()));
+        store.sync()?;
+        assert!(hint_written(temp_dir.path()));
+
+        drop(store);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..=100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,690 @@
+use std::io::{BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use kvs::utils::{Hello, Request, Respond, Welcome};
+use kvs::{
+    ErrorKind, KvStore, KvsClient, KvsEngine, KvsServer, Protocol, Resp, Result,
+    SharedQueueThreadPool, ThreadPool, Transaction, WriteBatch,
+};
+use tempfile::TempDir;
+
+/// start a server in the background, it lives until the test process exits
+fn spawn_server(dir: &TempDir, addr: &str, idle_timeout: Duration) -> Result<SocketAddr> {
+    spawn_server_with(dir, addr, idle_timeout, Protocol::Kvs)
+}
+
+fn spawn_server_with(
+    dir: &TempDir,
+    addr: &str,
+    idle_timeout: Duration,
+    protocol: Protocol,
+) -> Result<SocketAddr> {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let store = KvStore::open(dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let mut server = KvsServer::listen(store, pool, addr)?;
+    server.set_idle_timeout(idle_timeout);
+    server.set_protocol(protocol);
+    thread::spawn(move || server.serve().unwrap());
+    Ok(addr)
+}
+
+// One connection should serve any number of requests.
+#[test]
+fn persistent_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir, "127.0.0.1:4100", Duration::from_secs(60))?;
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..100 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    client.remove("key0".to_owned())?;
+    assert_eq!(client.get("key0".to_owned())?, None);
+
+    // other clients are served while the first one is still connected
+    let mut other = KvsClient::connect(addr)?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// The server should hang up on clients which stay silent for too long.
+#[test]
+fn idle_connection_closed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir, "127.0.0.1:4101", Duration::from_millis(200))?;
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    thread::sleep(Duration::from_secs(1));
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+/// send a command as an array of bulk strings and read the reply
+fn command(stream: &mut BufReader<TcpStream>, args: &[&str]) -> Result<Resp> {
+    let args = args.iter().map(|arg| Resp::Bulk(arg.as_bytes().to_vec()));
+    stream
+        .get_mut()
+        .write_all(&Resp::Array(args.collect()).ser()?)?;
+    Resp::read_from(stream)
+}
+
+#[test]
+fn resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server_with(
+        &temp_dir,
+        "127.0.0.1:4102",
+        Duration::from_secs(60),
+        Protocol::Resp,
+    )?;
+    let mut stream = BufReader::new(TcpStream::connect(addr)?);
+    let ok = Resp::Simple("OK".to_owned());
+    let bulk = |s: &str| Resp::Bulk(s.as_bytes().to_vec());
+
+    assert_eq!(
+        command(&mut stream, &["PING"])?,
+        Resp::Simple("PONG".to_owned())
+    );
+    assert_eq!(command(&mut stream, &["ping", "hi"])?, bulk("hi"));
+    assert_eq!(command(&mut stream, &["ECHO", "hello"])?, bulk("hello"));
+
+    assert_eq!(command(&mut stream, &["SET", "key1", "value1"])?, ok);
+    assert_eq!(command(&mut stream, &["SET", "key2", "value2"])?, ok);
+    assert_eq!(command(&mut stream, &["GET", "key1"])?, bulk("value1"));
+    assert_eq!(command(&mut stream, &["GET", "key3"])?, Resp::NullBulk);
+    assert_eq!(
+        command(&mut stream, &["EXISTS", "key1", "key2", "key3"])?,
+        Resp::Integer(2)
+    );
+    assert_eq!(
+        command(&mut stream, &["DEL", "key1", "key3"])?,
+        Resp::Integer(1)
+    );
+    assert_eq!(command(&mut stream, &["GET", "key1"])?, Resp::NullBulk);
+
+    assert!(matches!(command(&mut stream, &["GET"])?, Resp::Error(_)));
+    assert!(matches!(command(&mut stream, &["FOO"])?, Resp::Error(_)));
+    assert_eq!(
+        command(&mut stream, &["COMMAND", "COUNT"])?,
+        Resp::Integer(19)
+    );
+    match command(&mut stream, &["COMMAND", "INFO", "get", "foo"])? {
+        Resp::Array(info) => {
+            assert_eq!(info.len(), 2);
+            assert!(info[0].is_array());
+            assert!(info[1].is_null());
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+
+    // inline commands as typed into telnet
+    stream.get_mut().write_all(b"GET key2\r\n")?;
+    assert_eq!(Resp::read_from(&mut stream)?, bulk("value2"));
+
+    assert_eq!(command(&mut stream, &["QUIT"])?, ok);
+    assert!(Resp::read_from(&mut stream).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn resp3_hello() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server_with(
+        &temp_dir,
+        "127.0.0.1:4103",
+        Duration::from_secs(60),
+        Protocol::Resp,
+    )?;
+    let mut stream = BufReader::new(TcpStream::connect(addr)?);
+
+    // RESP2 until the client asks for more
+    assert_eq!(command(&mut stream, &["GET", "key1"])?, Resp::NullBulk);
+    assert!(command(&mut stream, &["HELLO"])?.is_array());
+    assert!(matches!(
+        command(&mut stream, &["HELLO", "4"])?,
+        Resp::Error(e) if e.starts_with("NOPROTO")
+    ));
+
+    match command(&mut stream, &["HELLO", "3", "SETNAME", "test"])? {
+        Resp::Map(pairs) => {
+            let proto = Resp::Bulk(b"proto".to_vec());
+            assert!(pairs.contains(&(proto, Resp::Integer(3))));
+        }
+        other => panic!("unexpected reply {:?}", other),
+    }
+    assert_eq!(command(&mut stream, &["GET", "key1"])?, Resp::Null);
+
+    assert!(command(&mut stream, &["HELLO", "2"])?.is_array());
+    assert_eq!(command(&mut stream, &["GET", "key1"])?, Resp::NullBulk);
+
+    Ok(())
+}
+
+#[test]
+fn pipeline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir, "127.0.0.1:4104", Duration::from_secs(60))?;
+    let mut client = KvsClient::connect(addr)?;
+
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    for i in 0..1000 {
+        pipeline.get(format!("key{}", i));
+    }
+    pipeline
+        .remove("key0".to_owned())
+        .remove("key0".to_owned())
+        .get("key0".to_owned());
+    let results = pipeline.execute()?;
+
+    assert_eq!(results.len(), 2003);
+    for res in &results[..1000] {
+        assert_eq!(res.as_ref().unwrap(), &None);
+    }
+    for (i, res) in results[1000..2000].iter().enumerate() {
+        assert_eq!(
+            res.as_ref().unwrap(),
+            &Some(format!("value{}", i).into_bytes())
+        );
+    }
+    assert!(results[2000].is_ok());
+    assert!(results[2001].is_err());
+    assert_eq!(results[2002].as_ref().unwrap(), &None);
+
+    // the connection is still usable afterwards
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+/// write a length prefixed frame as the kvs protocol does
+fn write_frame(stream: &mut TcpStream, payload: &[u8]) -> Result<()> {
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(payload)?;
+    Ok(())
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+// Clients must say hello with the protocol version of the server before sending requests.
+#[test]
+fn handshake() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir, "127.0.0.1:4105", Duration::from_secs(60))?;
+
+    KvsClient::connect(addr)?;
+
+    // any other version is rejected
+    for &version in &[0, 2] {
+        let mut stream = TcpStream::connect(addr)?;
+        write_frame(&mut stream, &bincode::serialize(&Hello { version })?)?;
+        let welcome: Welcome = bincode::deserialize(&read_frame(&mut stream)?)?;
+        assert!(matches!(welcome, Welcome::Rejected(_)));
+        assert_eq!(stream.read(&mut [0; 1])?, 0);
+    }
+
+    let mut stream = TcpStream::connect(addr)?;
+    write_frame(&mut stream, &bincode::serialize(&Hello { version: 1 })?)?;
+    let welcome: Welcome = bincode::deserialize(&read_frame(&mut stream)?)?;
+    assert!(matches!(welcome, Welcome::Accepted));
+
+    // requests the server does not know are answered with an error
+    let mut unknown = 100u32.to_le_bytes().to_vec();
+    unknown.extend_from_slice(b"future request");
+    write_frame(&mut stream, &unknown)?;
+    let res: Respond = bincode::deserialize(&read_frame(&mut stream)?)?;
+    assert!(matches!(res, Respond::Error { .. }));
+
+    // and the connection stays usable
+    let req = Request::Set(b"key1".to_vec(), b"value1".to_vec());
+    write_frame(&mut stream, &bincode::serialize(&req)?)?;
+    let res: Respond = bincode::deserialize(&read_frame(&mut stream)?)?;
+    assert!(matches!(res, Respond::Ok(None)));
+    let req = Request::Get(b"key1".to_vec());
+    write_frame(&mut stream, &bincode::serialize(&req)?)?;
+    let res: Respond = bincode::deserialize(&read_frame(&mut stream)?)?;
+    assert!(matches!(res, Respond::Ok(Some(ref value)) if value == b"value1"));
+
+    // a peer which has not said hello yet cannot make the server allocate a large frame
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&(64u32 * 1024 * 1024).to_be_bytes())?;
+    assert_eq!(stream.read(&mut [0; 1])?, 0);
+
+    Ok(())
+}
+
+// Errors keep their kind across the connection.
+#[test]
+fn typed_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir, "127.0.0.1:4106", Duration::from_secs(60))?;
+
+    let mut client = KvsClient::connect(addr)?;
+    let e = client.remove("key1".to_owned()).unwrap_err();
+    assert!(matches!(e.kind(), ErrorKind::KeyNotExist));
+
+    let results = client
+        .pipeline()
+        .set("key1".to_owned(), "value1".to_owned())
+        .remove("key1".to_owned())
+        .remove("key1".to_owned())
+        .execute()?;
+    assert!(results[1].is_ok());
+    assert!(matches!(
+        results[2].as_ref().unwrap_err().kind(),
+        ErrorKind::KeyNotExist
+    ));
+
+    Ok(())
+}
+
+// Keys and values do not have to be UTF-8.
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir, "127.0.0.1:4107", Duration::from_secs(60))?;
+
+    let key = vec![0, 159, 146, 150, 255];
+    let value: Vec<u8> = (0..=255).collect();
+    let mut client = KvsClient::connect(addr)?;
+    client.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(client.get_bytes(key.clone())?, Some(value.clone()));
+
+    // the string API refuses values it cannot represent
+    client.set_bytes(b"key1".to_vec(), value.clone())?;
+    let e = client.get("key1".to_owned()).unwrap_err();
+    assert!(matches!(e.kind(), ErrorKind::Encoding));
+
+    client.remove_bytes(key.clone())?;
+    assert_eq!(client.get_bytes(key)?, None);
+
+    Ok(())
+}
+
+// Scans are paged through the connection.
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir, "127.0.0.1:4108", Duration::from_secs(60))?;
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.set(format!("tenant1/{:04}", i), format!("value{}", i));
+        pipeline.set(format!("tenant2/{:04}", i), format!("value{}", i));
+    }
+    pipeline.execute()?;
+
+    let pairs: Vec<_> = client
+        .scan_prefix(b"tenant1/".to_vec())
+        .collect::<Result<_>>()?;
+    assert_eq!(pairs.len(), 1000);
+    for (i, (key, value)) in pairs.into_iter().enumerate() {
+        assert_eq!(key, format!("tenant1/{:04}", i).into_bytes());
+        assert_eq!(value, format!("value{}", i).into_bytes());
+    }
+
+    let keys: Vec<_> = client
+        .scan(b"tenant1/0998".to_vec()..=b"tenant2/0001".to_vec())
+        .map(|pair| pair.map(|(key, _)| String::from_utf8(key).unwrap()))
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        keys,
+        [
+            "tenant1/0998",
+            "tenant1/0999",
+            "tenant2/0000",
+            "tenant2/0001"
+        ]
+    );
+    assert_eq!(client.scan(..).count(), 2000);
+    assert_eq!(client.scan(b"tenant3".to_vec()..).count(), 0);
+
+    Ok(())
+}
+
+// Batches sent by clients are applied atomically.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir, "127.0.0.1:4109", Duration::from_secs(60))?;
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "value1").set("key2", "value2");
+    client.write_batch(batch)?;
+
+    let mut batch = WriteBatch::new();
+    batch.remove("key1").remove("key3");
+    let e = client.write_batch(batch).unwrap_err();
+    assert!(matches!(e.kind(), ErrorKind::KeyNotExist));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Conditional writes report whether they took place, over both protocols.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir, "127.0.0.1:4110", Duration::from_secs(60))?;
+
+    let mut client = KvsClient::connect(addr)?;
+    let key = || b"key1".to_vec();
+    assert!(client.set_if_absent(key(), b"value1".to_vec())?);
+    assert!(!client.set_if_absent(key(), b"value2".to_vec())?);
+    assert!(client.set_if_present(key(), b"value2".to_vec())?);
+    assert!(!client.set_if_present(b"key2".to_vec(), b"value1".to_vec())?);
+    assert!(!client.compare_and_swap(key(), None, Some(b"value3".to_vec()))?);
+    assert!(client.compare_and_swap(key(), Some(b"value2".to_vec()), None)?);
+    assert_eq!(client.get_bytes(key())?, None);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server_with(
+        &temp_dir,
+        "127.0.0.1:4111",
+        Duration::from_secs(60),
+        Protocol::Resp,
+    )?;
+    let mut stream = BufReader::new(TcpStream::connect(addr)?);
+    let ok = Resp::Simple("OK".to_owned());
+    assert_eq!(command(&mut stream, &["SET", "key1", "value1", "NX"])?, ok);
+    assert_eq!(
+        command(&mut stream, &["SET", "key1", "value2", "nx"])?,
+        Resp::NullBulk
+    );
+    assert_eq!(command(&mut stream, &["SET", "key1", "value3", "XX"])?, ok);
+    assert_eq!(
+        command(&mut stream, &["SET", "key2", "value1", "XX"])?,
+        Resp::NullBulk
+    );
+    assert!(matches!(
+        command(&mut stream, &["SET", "key1", "value1", "NX", "XX"])?,
+        Resp::Error(_)
+    ));
+    assert_eq!(
+        command(&mut stream, &["GET", "key1"])?,
+        Resp::Bulk(b"value3".to_vec())
+    );
+
+    Ok(())
+}
+
+// Transactions commit over the wire unless a watched key changed, MULTI/EXEC over RESP.
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir, "127.0.0.1:4112", Duration::from_secs(60))?;
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut other = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let (value, version) = client.get_versioned(b"key1".to_vec())?;
+    assert_eq!(value, Some(b"value1".to_vec()));
+    assert_ne!(version, 0);
+
+    let mut txn = Transaction::new();
+    client.get_watched(&mut txn, b"key1".to_vec())?;
+    txn.set("key2", "value2");
+    client.commit(txn)?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let mut txn = Transaction::new();
+    client.get_watched(&mut txn, b"key1".to_vec())?;
+    other.set("key1".to_owned(), "value3".to_owned())?;
+    txn.set("key2", "value4");
+    let e = client.commit(txn).unwrap_err();
+    assert!(matches!(e.kind(), ErrorKind::Conflict));
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server_with(
+        &temp_dir,
+        "127.0.0.1:4113",
+        Duration::from_secs(60),
+        Protocol::Resp,
+    )?;
+    let mut stream = BufReader::new(TcpStream::connect(addr)?);
+    let mut other = BufReader::new(TcpStream::connect(addr)?);
+    let ok = Resp::Simple("OK".to_owned());
+    let queued = Resp::Simple("QUEUED".to_owned());
+    let bulk = |s: &str| Resp::Bulk(s.as_bytes().to_vec());
+
+    // queued commands see the writes queued before them
+    assert_eq!(command(&mut stream, &["SET", "key1", "value1"])?, ok);
+    assert_eq!(command(&mut stream, &["MULTI"])?, ok);
+    assert_eq!(command(&mut stream, &["SET", "key2", "value2"])?, queued);
+    assert_eq!(command(&mut stream, &["GET", "key2"])?, queued);
+    assert_eq!(command(&mut stream, &["DEL", "key1", "key3"])?, queued);
+    assert_eq!(
+        command(&mut stream, &["EXEC"])?,
+        Resp::Array(vec![
+            Resp::Simple("OK".to_owned()),
+            bulk("value2"),
+            Resp::Integer(1)
+        ])
+    );
+    assert_eq!(command(&mut stream, &["GET", "key1"])?, Resp::NullBulk);
+
+    // a watched key changed by another client aborts EXEC
+    assert_eq!(command(&mut stream, &["WATCH", "key2"])?, ok);
+    assert_eq!(command(&mut other, &["SET", "key2", "value3"])?, ok);
+    assert_eq!(command(&mut stream, &["MULTI"])?, ok);
+    assert_eq!(command(&mut stream, &["SET", "key4", "value1"])?, queued);
+    assert_eq!(command(&mut stream, &["EXEC"])?, Resp::NullArray);
+    assert_eq!(command(&mut stream, &["GET", "key4"])?, Resp::NullBulk);
+
+    // EXEC forgets the watched keys, UNWATCH and DISCARD as well
+    assert_eq!(command(&mut stream, &["MULTI"])?, ok);
+    assert_eq!(command(&mut stream, &["SET", "key4", "value1"])?, queued);
+    assert_eq!(
+        command(&mut stream, &["EXEC"])?,
+        Resp::Array(vec![Resp::Simple("OK".to_owned())])
+    );
+    assert_eq!(command(&mut stream, &["WATCH", "key4"])?, ok);
+    assert_eq!(command(&mut stream, &["UNWATCH"])?, ok);
+    assert_eq!(command(&mut other, &["SET", "key4", "value2"])?, ok);
+    assert_eq!(command(&mut stream, &["MULTI"])?, ok);
+    assert_eq!(command(&mut stream, &["SET", "key4", "value3"])?, queued);
+    assert_eq!(command(&mut stream, &["DISCARD"])?, ok);
+    assert_eq!(command(&mut stream, &["GET", "key4"])?, bulk("value2"));
+
+    // errors while queueing discard the transaction
+    assert!(matches!(command(&mut stream, &["EXEC"])?, Resp::Error(_)));
+    assert_eq!(command(&mut stream, &["MULTI"])?, ok);
+    assert!(matches!(command(&mut stream, &["MULTI"])?, Resp::Error(_)));
+    assert!(matches!(
+        command(&mut stream, &["WATCH", "key4"])?,
+        Resp::Error(_)
+    ));
+    assert_eq!(command(&mut stream, &["SET", "key4", "value4"])?, queued);
+    assert!(matches!(command(&mut stream, &["GET"])?, Resp::Error(_)));
+    match command(&mut stream, &["EXEC"])? {
+        Resp::Error(e) => assert!(e.starts_with("EXECABORT")),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    assert_eq!(command(&mut stream, &["GET", "key4"])?, bulk("value2"));
+
+    // commands which do not touch keys are queued too, the others abort the transaction
+    assert_eq!(command(&mut stream, &["MULTI"])?, ok);
+    assert_eq!(command(&mut stream, &["PING"])?, queued);
+    assert_eq!(command(&mut stream, &["ECHO", "hello"])?, queued);
+    assert_eq!(command(&mut stream, &["UNWATCH"])?, queued);
+    assert_eq!(command(&mut stream, &["GET", "key4"])?, queued);
+    assert_eq!(
+        command(&mut stream, &["EXEC"])?,
+        Resp::Array(vec![
+            Resp::Simple("PONG".to_owned()),
+            bulk("hello"),
+            Resp::Simple("OK".to_owned()),
+            bulk("value2")
+        ])
+    );
+    assert_eq!(command(&mut stream, &["MULTI"])?, ok);
+    assert_eq!(command(&mut stream, &["SET", "key4", "value5"])?, queued);
+    assert!(matches!(
+        command(&mut stream, &["TTL", "key4"])?,
+        Resp::Error(_)
+    ));
+    assert_eq!(command(&mut stream, &["PING"])?, queued);
+    match command(&mut stream, &["EXEC"])? {
+        Resp::Error(e) => assert!(e.starts_with("EXECABORT")),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    assert_eq!(command(&mut stream, &["GET", "key4"])?, bulk("value2"));
+
+    Ok(())
+}
+
+// Keys expire over both protocols, TTL replies follow redis for missing and persistent keys.
+#[test]
+fn expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(&temp_dir, "127.0.0.1:4114", Duration::from_secs(60))?;
+
+    let mut client = KvsClient::connect(addr)?;
+    let hour = Duration::from_secs(3600);
+    client.set_with_ttl(
+        b"key1".to_vec(),
+        b"value1".to_vec(),
+        Duration::from_millis(100),
+    )?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(client.ttl(b"key1".to_vec())?.is_some());
+    assert_eq!(client.ttl(b"key2".to_vec())?, None);
+    let e = client.ttl(b"key3".to_vec()).unwrap_err();
+    assert!(matches!(e.kind(), ErrorKind::KeyNotExist));
+    assert!(client.expire(b"key2".to_vec(), hour)?);
+    assert!(!client.expire(b"key3".to_vec(), hour)?);
+    assert!(client.persist(b"key2".to_vec())?);
+    assert!(!client.persist(b"key2".to_vec())?);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(client.get_bytes(b"key1".to_vec())?, None);
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server_with(
+        &temp_dir,
+        "127.0.0.1:4115",
+        Duration::from_secs(60),
+        Protocol::Resp,
+    )?;
+    let mut stream = BufReader::new(TcpStream::connect(addr)?);
+    let ok = Resp::Simple("OK".to_owned());
+    let queued = Resp::Simple("QUEUED".to_owned());
+    assert_eq!(
+        command(&mut stream, &["SET", "key1", "value1", "PX", "100"])?,
+        ok
+    );
+    assert_eq!(
+        command(&mut stream, &["SET", "key2", "value2", "EX", "100", "NX"])?,
+        ok
+    );
+    assert_eq!(
+        command(&mut stream, &["SET", "key2", "value3", "NX", "EX", "100"])?,
+        Resp::NullBulk
+    );
+    assert_eq!(command(&mut stream, &["SET", "key3", "value3"])?, ok);
+    assert!(matches!(
+        command(&mut stream, &["SET", "key1", "value1", "EX", "0"])?,
+        Resp::Error(_)
+    ));
+    assert!(matches!(
+        command(
+            &mut stream,
+            &["SET", "key1", "value1", "EX", "1", "PX", "1"]
+        )?,
+        Resp::Error(_)
+    ));
+    assert!(matches!(
+        command(&mut stream, &["SET", "key1", "value1", "EX"])?,
+        Resp::Error(_)
+    ));
+
+    assert_eq!(command(&mut stream, &["TTL", "key2"])?, Resp::Integer(100));
+    match command(&mut stream, &["PTTL", "key2"])? {
+        Resp::Integer(ttl) => assert!(ttl > 99_000 && ttl <= 100_000),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    assert_eq!(command(&mut stream, &["TTL", "key3"])?, Resp::Integer(-1));
+    assert_eq!(command(&mut stream, &["PTTL", "key4"])?, Resp::Integer(-2));
+    assert_eq!(
+        command(&mut stream, &["EXPIRE", "key3", "100"])?,
+        Resp::Integer(1)
+    );
+    assert_eq!(
+        command(&mut stream, &["PEXPIRE", "key4", "100"])?,
+        Resp::Integer(0)
+    );
+    assert_eq!(
+        command(&mut stream, &["PERSIST", "key3"])?,
+        Resp::Integer(1)
+    );
+    assert_eq!(
+        command(&mut stream, &["PERSIST", "key3"])?,
+        Resp::Integer(0)
+    );
+    assert!(matches!(
+        command(&mut stream, &["EXPIRE", "key3", "soon"])?,
+        Resp::Error(_)
+    ));
+    // a time to live in the past removes the key
+    assert_eq!(
+        command(&mut stream, &["EXPIRE", "key3", "-1"])?,
+        Resp::Integer(1)
+    );
+    assert_eq!(command(&mut stream, &["GET", "key3"])?, Resp::NullBulk);
+
+    // SET with EX is queued, EXPIRE is refused inside MULTI
+    assert_eq!(command(&mut stream, &["MULTI"])?, ok);
+    assert_eq!(
+        command(&mut stream, &["SET", "key5", "value5", "PX", "100"])?,
+        queued
+    );
+    assert_eq!(
+        command(&mut stream, &["EXEC"])?,
+        Resp::Array(vec![Resp::Simple("OK".to_owned())])
+    );
+    assert_eq!(command(&mut stream, &["MULTI"])?, ok);
+    assert!(matches!(
+        command(&mut stream, &["EXPIRE", "key2", "1"])?,
+        Resp::Error(_)
+    ));
+    assert!(matches!(command(&mut stream, &["EXEC"])?, Resp::Error(_)));
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(command(&mut stream, &["GET", "key1"])?, Resp::NullBulk);
+    assert_eq!(command(&mut stream, &["GET", "key5"])?, Resp::NullBulk);
+    assert_eq!(
+        command(&mut stream, &["EXISTS", "key1", "key2"])?,
+        Resp::Integer(1)
+    );
+
+    Ok(())
+}